@@ -0,0 +1,143 @@
+use bevy::{app::AppExit, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use crate::simulation::SceneObjects;
+use crate::scene::{self, SceneCodec, SceneFile, SceneMetadata};
+use crate::ui::ReplaceScene;
+
+pub struct AutosavePlugin;
+
+impl Plugin for AutosavePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Autosave {
+                path: recovery_file_path(),
+                timer: Timer::new(AUTOSAVE_INTERVAL, TimerMode::Repeating),
+                last_written: Vec::new(),
+                recovery: None,
+            })
+            .add_systems(Startup, find_recovery_file)
+            .add_systems(Update, (recovery_prompt, autosave))
+            .add_systems(Last, remove_recovery_file_on_exit);
+    }
+}
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Resource)]
+struct Autosave {
+    /// `None` if there is no user data directory to save into.
+    path: Option<PathBuf>,
+    timer: Timer,
+    last_written: Vec<u8>,
+    /// A scene left behind by a session that didn't exit cleanly, and when it was last saved.
+    /// Autosaving is held off until the user decides what to do with it.
+    recovery: Option<(SceneFile, Option<SystemTime>)>,
+}
+
+fn recovery_file_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("horizons").join("recovery.horizons"))
+}
+
+fn find_recovery_file(mut autosave: ResMut<Autosave>) {
+    let Some(path) = autosave.path.clone() else {
+        warn!("no user data directory found; autosave is disabled");
+        return;
+    };
+    let Ok(bytes) = fs::read(&path) else { return };
+    match scene::decode(&bytes, SceneCodec::MessagePack) {
+        Ok(file) => {
+            let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+            autosave.recovery = Some((file, modified));
+        }
+        Err(e) => {
+            warn!("ignoring unreadable recovery file {}: {}", path.display(), e);
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+fn recovery_prompt(
+    mut contexts: EguiContexts,
+    mut autosave: ResMut<Autosave>,
+    mut replace_scene_writer: EventWriter<ReplaceScene>,
+) {
+    let Some((file, modified)) = &autosave.recovery else { return };
+    let mut restore = false;
+    let mut discard = false;
+    egui::Window::new("Restore unsaved session?")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            let title = if file.metadata.title.is_empty() { "Untitled scene" } else { &file.metadata.title };
+            ui.label(format!("{} ({} objects)", title, file.scene.objects.len()));
+            if let Some(elapsed) = modified.and_then(|modified| modified.elapsed().ok()) {
+                ui.label(format!("Last autosaved {} minutes ago", elapsed.as_secs() / 60));
+            }
+            ui.horizontal(|ui| {
+                restore = ui.button("Restore").clicked();
+                discard = ui.button("Discard").clicked();
+            });
+        });
+    if restore || discard {
+        let (file, _) = autosave.recovery.take().unwrap();
+        if restore {
+            replace_scene_writer.send(ReplaceScene(file));
+        } else if let Some(path) = &autosave.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn autosave(
+    time: Res<Time<Real>>,
+    mut autosave: ResMut<Autosave>,
+    scene_objects: Res<SceneObjects>,
+    scene_metadata: Res<SceneMetadata>,
+) {
+    if !autosave.timer.tick(time.delta()).just_finished() || autosave.recovery.is_some() {
+        return;
+    }
+    let Some(path) = autosave.path.clone() else { return };
+    // An empty board has nothing to recover, and clearing it shouldn't leave the scene from before
+    // to be offered after a crash.
+    if scene_objects.objects.is_empty() {
+        if !autosave.last_written.is_empty() {
+            let _ = fs::remove_file(&path);
+            autosave.last_written.clear();
+        }
+        return;
+    }
+    let file = SceneFile {
+        metadata: scene_metadata.clone(),
+        scene: scene_objects.clone(),
+    };
+    let bytes = scene::encode(&file, SceneCodec::MessagePack);
+    if bytes == autosave.last_written {
+        return;
+    }
+    // Write to a temporary file first so a crash mid-write can't corrupt the last good autosave.
+    let temp_path = path.with_extension("tmp");
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&temp_path, &bytes))
+        .and_then(|_| fs::rename(&temp_path, &path));
+    match result {
+        Ok(()) => autosave.last_written = bytes,
+        Err(e) => warn!("could not autosave to {}: {}", path.display(), e),
+    }
+}
+
+fn remove_recovery_file_on_exit(mut exit_events: EventReader<AppExit>, autosave: Res<Autosave>) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+    // Leave an undecided recovery file alone so it is offered again next time.
+    if let (Some(path), None) = (&autosave.path, &autosave.recovery) {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,134 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui, EguiContexts};
+use bevy_rapier2d::prelude::*;
+use crate::camera::MainCamera;
+use crate::chords::ChordEditor;
+use crate::pegs::{drag_peg, NewPegSettings, Octave, SelectedObject};
+use crate::simulation::{Pitch, SpawnObject, PEG_RESTITUTION};
+
+/// Drawing for bars, the pegs balls bounce off sideways. `SimulationPlugin` spawns and plays them.
+pub struct BarPlugin;
+
+impl Plugin for BarPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<BarDraft>()
+            .add_systems(FixedUpdate, draw_bar.after(drag_peg))
+            .add_systems(Update, show_bar_draft);
+    }
+}
+
+/// How thick bars are, in pixels.
+pub const THICKNESS: f32 = 12.;
+
+/// Bars shorter than this, in pixels, are left undrawn.
+const MIN_LENGTH: f32 = 20.;
+
+#[derive(Component)]
+pub struct Bar {
+    pub length: f32,
+    pub split: bool,
+}
+
+impl Bar {
+    /// The note laid out under `position` on a split bar, with the first note at the bar's left
+    /// end before it is rotated.
+    pub fn note_under(&self, notes: &[Pitch], transform: &Transform, position: Vec3) -> Option<Pitch> {
+        let along = (transform.rotation.inverse() * (position - transform.translation)).x / self.length + 0.5;
+        let index = ((along * notes.len() as f32).max(0.) as usize).min(notes.len().saturating_sub(1));
+        notes.get(index).copied()
+    }
+}
+
+pub fn bar_body(length: f32) -> impl Bundle {
+    (
+        RigidBody::Fixed,
+        Collider::cuboid(length / 2., THICKNESS / 2.),
+        Restitution {
+            coefficient: PEG_RESTITUTION,
+            combine_rule: CoefficientCombineRule::Max,
+        },
+    )
+}
+
+/// The ends of a bar centred on `x` and `y`.
+pub fn ends(x: f32, y: f32, length: f32, angle: f32) -> [[f32; 2]; 2] {
+    let half = length / 2. * Vec2::from_angle(angle.to_radians());
+    [(Vec2::new(x, y) - half).into(), (Vec2::new(x, y) + half).into()]
+}
+
+/// Where the bar being drawn starts, while it is being drawn.
+#[derive(Resource, Default)]
+pub struct BarDraft(Option<Vec2>);
+
+/// Shift-dragging from empty space draws a bar with the chord editor's notes, or the current
+/// octave's C if it has none.
+fn draw_bar(
+    input: Res<ButtonInput<MouseButton>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    selected_object: Res<SelectedObject>,
+    chord_editor: Res<ChordEditor>,
+    octave: Res<Octave>,
+    new_peg_settings: Res<NewPegSettings>,
+    mut draft: ResMut<BarDraft>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
+) {
+    let (camera, camera_transform) = primary_camera.single();
+    let Some(position) = primary_window
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+    else {
+        return;
+    };
+    let shift = keyboard_input.pressed(KeyCode::ShiftLeft) || keyboard_input.pressed(KeyCode::ShiftRight);
+    if input.just_pressed(MouseButton::Left) {
+        // `drag_peg` has already run, and selects whatever was under the cursor.
+        if shift && selected_object.0.is_none() && !contexts.ctx_mut().wants_pointer_input() {
+            draft.0 = Some(position);
+        }
+    } else if !input.pressed(MouseButton::Left) {
+        let Some(start) = draft.0.take() else { return };
+        if start.distance(position) < MIN_LENGTH {
+            return;
+        }
+        let notes = if chord_editor.notes.is_empty() {
+            vec![Pitch(((octave.0 + 1) * 12).min(Pitch::MAX.0 as u32) as u8)]
+        } else {
+            chord_editor.notes.clone()
+        };
+        spawn_event_writer.send(SpawnObject(new_peg_settings.bar(start, position, notes), None));
+    }
+}
+
+fn show_bar_draft(
+    mut gizmos: Gizmos,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    draft: Res<BarDraft>,
+) {
+    let Some(start) = draft.0 else { return };
+    let (camera, camera_transform) = primary_camera.single();
+    let Some(position) = primary_window
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+    else {
+        return;
+    };
+    let angle = (position.y - start.y).atan2(position.x - start.x);
+    gizmos.rect_2d((start + position) / 2., angle, Vec2::new(start.distance(position), THICKNESS), Color::GRAY);
+}
+
+/// Edits a bar's shape and how its notes are laid out.
+pub fn bar_editor(ui: &mut egui::Ui, length: &mut f32, angle: &mut f32, split: &mut bool) {
+    ui.add(egui::DragValue::new(length).clamp_range(MIN_LENGTH..=2000.).prefix("Length ").suffix(" px"));
+    ui.add(egui::Slider::new(angle, -90.0..=90.).text("Angle").suffix("°"));
+    ui.checkbox(split, "Spread notes along the bar")
+        .on_hover_text("Balls play the note under where they land, from the left end to the right");
+}
@@ -0,0 +1,459 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use crate::instruments::InstrumentId;
+use crate::simulation::Pitch;
+use crate::sound::PreviewNotes;
+use crate::synth::Voice;
+
+/// A chord parsed from a symbol such as `Cmaj7`, `Bb7#9` or `F#m7b5/E`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Chord {
+    /// Pitch class of the root, where 0 is C.
+    root: u8,
+    /// Semitones above the root of each note, including the root itself.
+    intervals: BTreeSet<u8>,
+    /// Pitch class of a slash chord's bass note.
+    bass: Option<u8>,
+}
+
+/// Reads a note name without an octave, such as `C`, `F#` or `Bb`, from the start of `text`.
+fn parse_pitch_class(text: &str) -> Option<(u8, &str)> {
+    let mut chars = text.chars();
+    let natural = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut class: i32 = natural;
+    let mut rest = chars.as_str();
+    loop {
+        if let Some(after) = rest.strip_prefix(['#', '♯']) {
+            class += 1;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix(['b', '♭']) {
+            class -= 1;
+            rest = after;
+        } else {
+            return Some((class.rem_euclid(12) as u8, rest));
+        }
+    }
+}
+
+/// Strips the first of `prefixes` that `text` starts with.
+fn eat(text: &mut &str, prefixes: &[&str]) -> bool {
+    match prefixes.iter().find_map(|prefix| text.strip_prefix(prefix)) {
+        Some(rest) => {
+            *text = rest;
+            true
+        }
+        None => false,
+    }
+}
+
+fn eat_number(text: &mut &str) -> Option<u32> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let number = text[..digits].parse().ok()?;
+    *text = &text[digits..];
+    Some(number)
+}
+
+/// Semitones above the root of a scale degree such as the 9th in `add9`.
+fn degree_interval(degree: u32) -> Option<u8> {
+    match degree {
+        2 | 9 => Some(14),
+        4 | 11 => Some(17),
+        6 | 13 => Some(21),
+        _ => None,
+    }
+}
+
+impl FromStr for Chord {
+    type Err = String;
+
+    /// Accepts a root, a quality (`m`, `dim`, `aug`, `ø`, `sus2`, `sus4`, `5`), an extension (`6`,
+    /// `7`, `maj7`, `9`, `11`, `13`), any number of alterations (`b5`, `#5`, `b9`, `#9`, `#11`,
+    /// `b13`, `add9`, `no3`, ...) and a slash bass note.
+    fn from_str(text: &str) -> Result<Self, String> {
+        let text: String = text.chars().filter(|&c| !c.is_whitespace() && c != '(' && c != ')').collect();
+        let (symbol, bass) = match text.split_once('/') {
+            Some((symbol, bass)) => match parse_pitch_class(bass) {
+                Some((bass, "")) => (symbol, Some(bass)),
+                _ => return Err(format!("`{}` is not a bass note", bass)),
+            },
+            None => (text.as_str(), None),
+        };
+        let Some((root, mut rest)) = parse_pitch_class(symbol) else {
+            return Err("a chord starts with its root, e.g. C or F#".into());
+        };
+
+        let mut third = Some(4);
+        let mut fifth = Some(7);
+        let mut seventh = None;
+        let mut extensions = BTreeSet::new();
+        let mut major_seventh = false;
+        let mut diminished = false;
+        if eat(&mut rest, &["maj", "Maj", "M"]) {
+            major_seventh = true;
+        } else if eat(&mut rest, &["Δ", "∆"]) {
+            major_seventh = true;
+            seventh = Some(11);
+        } else if eat(&mut rest, &["min", "mi", "m", "-"]) {
+            third = Some(3);
+            major_seventh = eat(&mut rest, &["maj", "Maj", "M", "Δ", "∆"]);
+        } else if eat(&mut rest, &["dim", "°", "o"]) {
+            third = Some(3);
+            fifth = Some(6);
+            diminished = true;
+        } else if eat(&mut rest, &["ø"]) {
+            third = Some(3);
+            fifth = Some(6);
+            seventh = Some(10);
+        } else if eat(&mut rest, &["aug", "+"]) {
+            fifth = Some(8);
+        }
+
+        match eat_number(&mut rest) {
+            None => {}
+            Some(5) if third == Some(4) && fifth == Some(7) && !major_seventh => third = None,
+            Some(6) => {
+                extensions.insert(9);
+            }
+            Some(extension @ (7 | 9 | 11 | 13)) => {
+                seventh = Some(if major_seventh { 11 } else if diminished { 9 } else { 10 });
+                if extension >= 9 {
+                    extensions.insert(14);
+                }
+                if extension == 11 {
+                    extensions.insert(17);
+                }
+                if extension == 13 {
+                    extensions.insert(21);
+                }
+            }
+            Some(number) => return Err(format!("{} is not a chord extension", number)),
+        }
+
+        while !rest.is_empty() {
+            if eat(&mut rest, &["sus2"]) {
+                third = Some(2);
+            } else if eat(&mut rest, &["sus4", "sus"]) {
+                third = Some(5);
+            } else if eat(&mut rest, &["add"]) {
+                let degree = eat_number(&mut rest).ok_or("add needs a degree, e.g. add9")?;
+                extensions.insert(degree_interval(degree).ok_or_else(|| format!("can't add a {}", degree))?);
+            } else if eat(&mut rest, &["no3", "omit3"]) {
+                third = None;
+            } else if eat(&mut rest, &["no5", "omit5"]) {
+                fifth = None;
+            } else if eat(&mut rest, &["b5", "♭5", "-5"]) {
+                fifth = Some(6);
+            } else if eat(&mut rest, &["#5", "♯5", "+5"]) {
+                fifth = Some(8);
+            } else if eat(&mut rest, &["b9", "♭9", "-9"]) {
+                extensions.remove(&14);
+                extensions.insert(13);
+            } else if eat(&mut rest, &["#9", "♯9", "+9"]) {
+                extensions.remove(&14);
+                extensions.insert(15);
+            } else if eat(&mut rest, &["#11", "♯11", "+11"]) {
+                extensions.remove(&17);
+                extensions.insert(18);
+            } else if eat(&mut rest, &["b13", "♭13", "-13"]) {
+                extensions.remove(&21);
+                extensions.insert(20);
+            } else {
+                return Err(format!("`{}` is not part of a chord symbol", rest));
+            }
+        }
+
+        let intervals = [Some(0), third, fifth, seventh].into_iter().flatten().chain(extensions).collect();
+        Ok(Chord { root, intervals, bass })
+    }
+}
+
+/// How a chord's notes are spread out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Voicing {
+    /// Every note as close above the lowest as it goes.
+    Close,
+    /// The second-highest note dropped an octave.
+    Drop2,
+    /// The third-highest note dropped an octave.
+    Drop3,
+    /// Every other note from the bottom raised an octave.
+    Spread,
+}
+
+impl Voicing {
+    pub const ALL: [Voicing; 4] = [Voicing::Close, Voicing::Drop2, Voicing::Drop3, Voicing::Spread];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Voicing::Close => "Close",
+            Voicing::Drop2 => "Drop 2",
+            Voicing::Drop3 => "Drop 3",
+            Voicing::Spread => "Spread",
+        }
+    }
+}
+
+impl Chord {
+    /// How many ways the chord can be inverted, counting root position, which is how many notes it
+    /// has above the bass.
+    pub fn inversions(&self) -> usize {
+        self.intervals.len()
+    }
+
+    /// The chord's notes with its root in `octave` (middle C is in octave 4), lowest first.
+    /// Each inversion moves the lowest note up an octave before the voicing is applied; a slash
+    /// bass always goes below everything else. Notes outside the MIDI range are left out.
+    pub fn notes(&self, octave: u32, inversion: usize, voicing: Voicing) -> Vec<Pitch> {
+        let root = (octave as i32 + 1) * 12 + self.root as i32;
+        let mut notes: Vec<i32> = self.intervals.iter().map(|&interval| root + interval as i32).collect();
+        for _ in 0..inversion % notes.len() {
+            notes[0] += 12;
+            notes.sort();
+        }
+        let length = notes.len();
+        match voicing {
+            Voicing::Close => {}
+            Voicing::Drop2 if length >= 3 => notes[length - 2] -= 12,
+            Voicing::Drop3 if length >= 4 => notes[length - 3] -= 12,
+            Voicing::Spread => {
+                for note in notes.iter_mut().skip(1).step_by(2) {
+                    *note += 12;
+                }
+            }
+            Voicing::Drop2 | Voicing::Drop3 => {}
+        }
+        notes.sort();
+        if let Some(bass) = self.bass {
+            let lowest = notes[0];
+            let below = (lowest - bass as i32).rem_euclid(12);
+            notes.insert(0, lowest - if below == 0 { 12 } else { below });
+        }
+        notes.dedup();
+        notes
+            .into_iter()
+            .filter(|&note| (0..=Pitch::MAX.0 as i32).contains(&note))
+            .map(|note| Pitch(note as u8))
+            .collect()
+    }
+}
+
+/// The chord Enter places at the cursor, as built in the chord editor.
+#[derive(Resource)]
+pub struct ChordEditor {
+    symbol: String,
+    /// The octave of the chord's root.
+    octave: u32,
+    inversion: usize,
+    voicing: Voicing,
+    /// How many inversions the current chord has.
+    inversions: usize,
+    /// The notes from the symbol, plus or minus any keys clicked on the keyboard.
+    pub notes: Vec<Pitch>,
+    error: Option<String>,
+}
+
+impl Default for ChordEditor {
+    fn default() -> Self {
+        let mut editor = ChordEditor {
+            symbol: "Cmaj7".into(),
+            octave: 4,
+            inversion: 0,
+            voicing: Voicing::Close,
+            inversions: 1,
+            notes: Vec::new(),
+            error: None,
+        };
+        editor.update_notes();
+        editor
+    }
+}
+
+impl ChordEditor {
+    fn update_notes(&mut self) {
+        match self.symbol.parse::<Chord>() {
+            Ok(chord) => {
+                self.inversions = chord.inversions();
+                self.inversion = self.inversion.min(self.inversions - 1);
+                self.notes = chord.notes(self.octave, self.inversion, self.voicing);
+                self.error = None;
+            }
+            Err(e) => {
+                self.notes.clear();
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+/// The side panel's chord editor.
+#[derive(SystemParam)]
+pub struct ChordEditorUi<'w> {
+    editor: ResMut<'w, ChordEditor>,
+    preview_writer: EventWriter<'w, PreviewNotes>,
+}
+
+impl ChordEditorUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui, instrument: &InstrumentId) {
+        let editor = &mut *self.editor;
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Symbol");
+            changed |= ui.text_edit_singleline(&mut editor.symbol).on_hover_text("e.g. Cmaj7, Am9, F#m7b5/E").changed();
+        });
+        ui.horizontal(|ui| {
+            changed |= ui.add(egui::DragValue::new(&mut editor.octave).clamp_range(0..=9).prefix("Octave ")).changed();
+            changed |= ui
+                .add(egui::DragValue::new(&mut editor.inversion).clamp_range(0..=editor.inversions - 1).prefix("Inversion "))
+                .changed();
+        });
+        egui::ComboBox::from_label("Voicing")
+            .selected_text(editor.voicing.name())
+            .show_ui(ui, |ui| {
+                for voicing in Voicing::ALL {
+                    changed |= ui.selectable_value(&mut editor.voicing, voicing, voicing.name()).changed();
+                }
+            });
+        if changed {
+            editor.update_notes();
+        }
+        if let Some(error) = &editor.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        piano(ui, editor.octave, &mut editor.notes);
+        if editor.notes.is_empty() {
+            ui.label("No notes");
+        } else {
+            ui.label(editor.notes.iter().map(|note| note.to_string()).collect::<Vec<_>>().join(" "));
+        }
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!editor.notes.is_empty(), egui::Button::new("Preview")).clicked() {
+                self.preview_writer.send(PreviewNotes {
+                    notes: editor.notes.clone(),
+                    voice: Voice::default(),
+                    instrument: instrument.clone(),
+                });
+            }
+            if ui.button("Reset").on_hover_text("Undo clicks on the keyboard").clicked() {
+                editor.update_notes();
+            }
+        });
+        ui.weak("Press Enter to place the chord at the cursor");
+    }
+}
+
+/// Semitones above C of the black keys, and how many white keys along their centres are.
+const BLACK_KEYS: [(u8, f32); 5] = [(1, 1.), (3, 2.), (6, 4.), (8, 5.), (10, 6.)];
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// An on-screen keyboard with `notes` held down, covering at least two octaves from `octave`.
+/// Clicking a key adds or removes its note.
+fn piano(ui: &mut egui::Ui, octave: u32, notes: &mut Vec<Pitch>) {
+    // Octaves are counted from C-1 here, so that octave `n` starts at MIDI note `12 * n`.
+    let octave_of = |note: &Pitch| note.0 as u32 / 12;
+    let first = notes.iter().map(octave_of).min().unwrap_or(u32::MAX).min(octave + 1);
+    let last = notes.iter().map(octave_of).max().unwrap_or(0).max(octave + 2).min(octave_of(&Pitch::MAX));
+    let octaves = last - first + 1;
+    let width = ui.available_width();
+    let key_width = width / (octaves * 7) as f32;
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(width, 48.), egui::Sense::click());
+
+    let mut white = Vec::new();
+    let mut black = Vec::new();
+    for (i, index) in (first..=last).enumerate() {
+        let left = rect.left() + (i * 7) as f32 * key_width;
+        for (j, &step) in WHITE_KEYS.iter().enumerate() {
+            let key = egui::Rect::from_min_size(egui::pos2(left + j as f32 * key_width, rect.top()), egui::vec2(key_width, rect.height()));
+            white.push((index * 12 + step as u32, key));
+        }
+        for &(step, centre) in &BLACK_KEYS {
+            let key = egui::Rect::from_center_size(
+                egui::pos2(left + centre * key_width, rect.top() + rect.height() * 0.3),
+                egui::vec2(key_width * 0.6, rect.height() * 0.6),
+            );
+            black.push((index * 12 + step as u32, key));
+        }
+    }
+    white.retain(|&(note, _)| note <= Pitch::MAX.0 as u32);
+    black.retain(|&(note, _)| note <= Pitch::MAX.0 as u32);
+
+    if let Some(pointer) = response.interact_pointer_pos().filter(|_| response.clicked()) {
+        let clicked = black.iter().chain(&white).find(|(_, key)| key.contains(pointer));
+        if let Some(&(note, _)) = clicked {
+            let note = Pitch(note as u8);
+            match notes.iter().position(|&held| held == note) {
+                Some(index) => {
+                    notes.remove(index);
+                }
+                None => {
+                    notes.push(note);
+                    notes.sort();
+                }
+            }
+        }
+    }
+
+    let painter = ui.painter_at(rect);
+    let held = ui.visuals().selection.bg_fill;
+    let outline = egui::Stroke::new(1., egui::Color32::DARK_GRAY);
+    for (keys, colour) in [(&white, egui::Color32::WHITE), (&black, egui::Color32::BLACK)] {
+        for &(note, key) in keys {
+            let fill = if notes.contains(&Pitch(note as u8)) { held } else { colour };
+            painter.rect(key, 1., fill, outline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(symbol: &str) -> Chord {
+        symbol.parse().unwrap()
+    }
+
+    fn notes(symbol: &str) -> Vec<u8> {
+        chord(symbol).notes(4, 0, Voicing::Close).into_iter().map(|Pitch(note)| note).collect()
+    }
+
+    #[test]
+    fn parses_extended_chords() {
+        assert_eq!(chord("Cmaj7"), Chord { root: 0, intervals: BTreeSet::from([0, 4, 7, 11]), bass: None });
+        assert_eq!(notes("Cmaj7"), [60, 64, 67, 71]);
+        assert_eq!(chord("CΔ7"), chord("Cmaj7"));
+        assert_eq!(chord("Bb7#9"), Chord { root: 10, intervals: BTreeSet::from([0, 4, 7, 10, 15]), bass: None });
+        assert_eq!(notes("Asus4"), [69, 74, 76]);
+    }
+
+    #[test]
+    fn parses_half_diminished_slash_chords() {
+        assert_eq!(chord("F#m7b5/E"), Chord { root: 6, intervals: BTreeSet::from([0, 3, 6, 10]), bass: Some(4) });
+        assert_eq!(chord("F#ø/E"), chord("F#m7b5/E"));
+        assert_eq!(notes("F#m7b5/E"), [64, 66, 69, 72, 76]);
+    }
+
+    #[test]
+    fn puts_slash_bass_below_the_chord() {
+        assert_eq!(notes("C/G"), [55, 60, 64, 67]);
+        // A bass that is already the lowest note is doubled an octave down.
+        assert_eq!(notes("C/C"), [48, 60, 64, 67]);
+        assert_eq!(chord("C/G").notes(4, 1, Voicing::Close), [Pitch(55), Pitch(64), Pitch(67), Pitch(72)]);
+    }
+
+    #[test]
+    fn rejects_invalid_symbols() {
+        for symbol in ["", "H", "c", "C8", "Cadd", "Cadd3", "Cmaj7/X", "C/", "Cxyz"] {
+            assert!(symbol.parse::<Chord>().is_err(), "`{}` parsed", symbol);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use bevy::prelude::*;
+use std::fs;
+use crate::midi::{self, RecordedNote};
+use crate::mixdown::{self, SampleBank};
+use crate::scene::{self, SceneCodec, SceneFile};
+use crate::simulation::{headless_app, NoteEvent, StartPerformance, TIMESTEP};
+use crate::spatial::SpatialAudio;
+use crate::dynamics::Dynamics;
+use crate::instruments::Instruments;
+use crate::synth::Voice;
+use crate::voices::VoiceSettings;
+
+const USAGE: &str = "usage:
+    horizons simulate <scene> [--seconds N]
+    horizons render <scene> [--seconds N] -o <out.wav|out.mid>
+
+Dynamics, spatial audio and voice settings aren't saved with scenes, so WAV renders use their
+defaults rather than whatever the editor was last set to.";
+
+/// Runs a command-line tool instead of the editor, returning the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let result = match args[0].as_str() {
+        "simulate" => simulate(&args[1..]),
+        "render" => render(&args[1..]),
+        _ => Err(USAGE.into()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(message) => {
+            eprintln!("{}", message);
+            1
+        }
+    }
+}
+
+struct Options {
+    scene: String,
+    seconds: f32,
+    output: Option<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut scene = None;
+    let mut seconds = 30.;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seconds" => {
+                seconds = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|&value: &f32| value > 0.)
+                    .ok_or("--seconds needs a positive number")?;
+            }
+            "-o" | "--output" => output = Some(args.next().ok_or("-o needs a file name")?.clone()),
+            _ if scene.is_none() && !arg.starts_with("--") => scene = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`\n{}", arg, USAGE)),
+        }
+    }
+    Ok(Options { scene: scene.ok_or(USAGE)?, seconds, output })
+}
+
+fn load_scene(path: &str) -> Result<SceneFile, String> {
+    let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    scene::decode(&bytes, SceneCodec::from_file_name(path)).map_err(|e| format!("could not load {}: {}", path, e))
+}
+
+/// Runs the scene from Start for the given number of seconds, calling `on_note` with the tick
+/// each note event happened on.
+fn run_performance(file: &SceneFile, seconds: f32, mut on_note: impl FnMut(u64, NoteEvent)) {
+    let mut app = headless_app(file);
+    // Let the scene spawn before dropping the balls.
+    app.update();
+    app.world.send_event(StartPerformance);
+    let ticks = (seconds / TIMESTEP).ceil() as u64;
+    for tick in 0..ticks {
+        app.update();
+        for note_event in app.world.resource_mut::<Events<NoteEvent>>().drain() {
+            on_note(tick, note_event);
+        }
+    }
+}
+
+/// Prints every note the scene plays, one hit per line.
+fn simulate(args: &[String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let file = load_scene(&options.scene)?;
+    run_performance(&file, options.seconds, |tick, note_event| {
+        let sound = match &note_event.voice {
+            Voice::Drum(drum) => drum.name().to_owned(),
+            _ => note_event.notes.iter().map(|note| note.to_string()).collect::<Vec<_>>().join(" "),
+        };
+        println!("{:.4}\t{}", tick as f32 * TIMESTEP, sound);
+    });
+    Ok(())
+}
+
+/// Renders the scene to a WAV file, placing each note exactly on the tick it was hit, or to a
+/// MIDI file if the output ends in `.mid`. The mix uses the default `Dynamics`, `SpatialAudio`
+/// and `VoiceSettings`, since those live in the editor rather than the scene.
+fn render(args: &[String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let output = options.output.ok_or("render needs an output file (-o out.wav)")?;
+    let file = load_scene(&options.scene)?;
+    if output.to_ascii_lowercase().ends_with(".mid") {
+        let mut notes = Vec::new();
+        run_performance(&file, options.seconds, |tick, note_event| {
+            notes.extend(RecordedNote::from_event(tick as f32 * TIMESTEP, &note_event));
+        });
+        let bytes = midi::write_smf(&notes, file.metadata.tempo, file.metadata.time_signature, &file.metadata.title);
+        return fs::write(&output, bytes).map_err(|e| format!("could not write {}: {}", output, e));
+    }
+    let mut hits = Vec::new();
+    run_performance(&file, options.seconds, |tick, note_event| hits.push((tick, note_event)));
+    let tuning = &file.metadata.tuning;
+    let bank = SampleBank::load(&Instruments::discover(), tuning, &hits)?;
+    mixdown::write_wav(&output, &mixdown::mix(&hits, options.seconds, &bank, tuning, &Dynamics::default(), &SpatialAudio::default(), &VoiceSettings::default()))
+}
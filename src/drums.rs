@@ -0,0 +1,186 @@
+use bevy::{
+    audio::{AddAudioSource, Decodable, Source},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+use std::time::Duration;
+use crate::synth::SAMPLE_RATE;
+
+/// Registers `DrumHit` as an audio source for the built-in drum kit.
+pub struct DrumPlugin;
+
+impl Plugin for DrumPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<DrumHit>();
+    }
+}
+
+/// The sound of a percussion peg. The built-in drums are synthesized, so they need no samples.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum Drum {
+    #[default]
+    Kick,
+    Snare,
+    HiHat,
+    Clap,
+    /// An ogg file, as a path inside the assets folder (e.g. `sounds/cowbell.ogg`).
+    Custom(String),
+}
+
+impl Drum {
+    pub const BUILT_IN: [Drum; 4] = [Drum::Kick, Drum::Snare, Drum::HiHat, Drum::Clap];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Drum::Kick => "Kick",
+            Drum::Snare => "Snare",
+            Drum::HiHat => "Hi-hat",
+            Drum::Clap => "Clap",
+            Drum::Custom(_) => "Custom sample",
+        }
+    }
+
+    /// The General MIDI percussion key for the drum, sent on channel 10.
+    pub fn midi_note(&self) -> u8 {
+        match self {
+            Drum::Kick => 36,
+            Drum::Snare => 38,
+            Drum::HiHat => 42,
+            Drum::Clap => 39,
+            // Side stick; a custom sample could be anything.
+            Drum::Custom(_) => 37,
+        }
+    }
+
+    /// Percussion pegs are colored by drum rather than by pitch.
+    pub fn color(&self) -> Color {
+        match self {
+            Drum::Kick => Color::rgb(6., 1.2, 0.),
+            Drum::Snare => Color::rgb(5., 4., 0.3),
+            Drum::HiHat => Color::rgb(0.3, 4., 5.),
+            Drum::Clap => Color::rgb(5., 0.5, 2.5),
+            Drum::Custom(_) => Color::rgb(3., 3., 3.),
+        }
+    }
+}
+
+/// One hit of a built-in drum, played with an `AudioSourceBundle<DrumHit>`. Custom drums are
+/// played from their sample instead.
+#[derive(Asset, TypePath, Clone)]
+pub struct DrumHit(pub Drum);
+
+impl DrumHit {
+    fn duration(&self) -> f32 {
+        match self.0 {
+            Drum::Kick => 0.5,
+            Drum::Snare => 0.3,
+            Drum::HiHat => 0.12,
+            Drum::Clap => 0.35,
+            Drum::Custom(_) => 0.,
+        }
+    }
+}
+
+impl Decodable for DrumHit {
+    type DecoderItem = f32;
+    type Decoder = DrumDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        DrumDecoder {
+            drum: self.0.clone(),
+            frame: 0,
+            frames: (self.duration() * SAMPLE_RATE as f32) as u64,
+            duration: self.duration(),
+            phase: 0.,
+            // Every hit uses the same noise, so renders come out the same each time.
+            noise: 0x9e37_79b9,
+            last_noise: 0.,
+        }
+    }
+}
+
+/// Renders a `DrumHit` one mono sample at a time.
+pub struct DrumDecoder {
+    drum: Drum,
+    frame: u64,
+    frames: u64,
+    duration: f32,
+    phase: f32,
+    /// xorshift state.
+    noise: u32,
+    last_noise: f32,
+}
+
+impl DrumDecoder {
+    /// White noise from -1 to 1.
+    fn white_noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2. - 1.
+    }
+
+    /// Noise with the low end taken out, for the snare's wires, hats and claps.
+    fn bright_noise(&mut self) -> f32 {
+        let noise = self.white_noise();
+        let bright = noise - self.last_noise;
+        self.last_noise = noise;
+        bright * 0.5
+    }
+
+    fn tone(&mut self, frequency: f32) -> f32 {
+        self.phase = (self.phase + frequency / SAMPLE_RATE as f32).fract();
+        (self.phase * TAU).sin()
+    }
+}
+
+impl Iterator for DrumDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame >= self.frames {
+            return None;
+        }
+        let time = self.frame as f32 / SAMPLE_RATE as f32;
+        self.frame += 1;
+        let sample = match self.drum {
+            Drum::Kick => {
+                // The pitch drops quickly from a click to a low thump.
+                let frequency = 50. + 110. * (-time / 0.03).exp();
+                self.tone(frequency) * (-time / 0.15).exp()
+            }
+            Drum::Snare => {
+                let body = self.tone(185.) * (-time / 0.05).exp();
+                body * 0.5 + self.bright_noise() * (-time / 0.08).exp()
+            }
+            Drum::HiHat => self.bright_noise() * (-time / 0.025).exp(),
+            Drum::Clap => {
+                // Three quick claps, then the room.
+                let since_clap = if time < 0.03 { time % 0.01 } else { time - 0.02 };
+                let decay = if time < 0.03 { 0.004 } else { 0.07 };
+                self.bright_noise() * (-since_clap / decay).exp()
+            }
+            Drum::Custom(_) => 0.,
+        };
+        Some(sample * 0.6)
+    }
+}
+
+impl Source for DrumDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f32(self.duration))
+    }
+}
@@ -0,0 +1,76 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+
+/// How hard a ball hits a peg sets how loud the note plays, and optionally how bright.
+#[derive(Resource, Clone)]
+pub struct Dynamics {
+    /// Bounce speeds, in pixels per second, at or below this play at `quietest`.
+    pub soft_speed: f32,
+    /// Bounce speeds at or above this play at full volume.
+    pub hard_speed: f32,
+    /// The shape of the curve in between: 1 is linear, higher keeps soft hits soft for longer and
+    /// lower brings them up.
+    pub curve: f32,
+    /// Volume of the softest hits, from 0 to 1.
+    pub quietest: f32,
+    /// How far the softest hits close a synth voice's filter, from 0 (not at all) to 1.
+    pub brightness: f32,
+}
+
+impl Default for Dynamics {
+    fn default() -> Self {
+        Dynamics {
+            soft_speed: 20.,
+            hard_speed: 400.,
+            curve: 1.5,
+            quietest: 0.05,
+            brightness: 0.5,
+        }
+    }
+}
+
+/// How one hit should sound.
+#[derive(Clone, Copy)]
+pub struct Touch {
+    /// Playback volume, from 0 to 1.
+    pub volume: f32,
+    /// Multiplies a synth voice's filter cutoff.
+    pub brightness: f32,
+}
+
+impl Touch {
+    /// For notes that weren't played by a ball, such as previews.
+    pub const FULL: Touch = Touch { volume: 1., brightness: 1. };
+}
+
+impl Dynamics {
+    pub fn touch(&self, speed: f32) -> Touch {
+        let range = (self.hard_speed - self.soft_speed).max(1.);
+        let level = ((speed - self.soft_speed) / range).clamp(0., 1.).powf(self.curve);
+        Touch {
+            volume: self.quietest + (1. - self.quietest) * level,
+            brightness: 1. - self.brightness * (1. - level),
+        }
+    }
+}
+
+/// The side panel's dynamics settings.
+#[derive(SystemParam)]
+pub struct DynamicsUi<'w> {
+    dynamics: ResMut<'w, Dynamics>,
+}
+
+impl DynamicsUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let dynamics = &mut *self.dynamics;
+        ui.add(egui::Slider::new(&mut dynamics.soft_speed, 0.0..=1000.).text("Softest hit").suffix(" px/s"));
+        ui.add(egui::Slider::new(&mut dynamics.hard_speed, 0.0..=1000.).text("Hardest hit").suffix(" px/s"));
+        ui.add(egui::Slider::new(&mut dynamics.curve, 0.25..=4.).logarithmic(true).text("Curve"));
+        ui.add(egui::Slider::new(&mut dynamics.quietest, 0.0..=1.).text("Quietest"));
+        ui.add(egui::Slider::new(&mut dynamics.brightness, 0.0..=1.).text("Brightness"))
+            .on_hover_text("How much darker soft hits make synth voices");
+        if dynamics.hard_speed < dynamics.soft_speed {
+            dynamics.hard_speed = dynamics.soft_speed;
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::simulation::{despawn_object, DespawnObject, Object, ObjectAdded, SceneObjects, SpawnObject};
+
+pub struct HistoryPlugin;
+
+impl Plugin for HistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<History>()
+            .add_event::<Undo>()
+            .add_event::<Redo>()
+            .add_event::<ApplyEdit>()
+            .add_systems(Update, undo_shortcuts)
+            .add_systems(FixedUpdate, record_added_objects)
+            .add_systems(FixedUpdate, apply_history_events.before(despawn_object));
+    }
+}
+
+/// Oldest edits are forgotten once the undo stack grows past this.
+const MAX_HISTORY: usize = 256;
+
+/// A reversible change to `SceneObjects`.
+#[derive(Clone)]
+pub enum Edit {
+    Spawn { id: u32, object: Object },
+    Delete { id: u32, object: Object },
+    /// Moves and note edits both replace the whole object.
+    Modify { id: u32, before: Object, after: Object },
+    Clear { objects: BTreeMap<u32, Object>, object_count: u32 },
+    /// Several edits made by one command, undone together.
+    Group { name: &'static str, edits: Vec<Edit> },
+}
+
+impl Edit {
+    pub fn describe(&self) -> String {
+        match self {
+            Edit::Spawn { object, .. } => format!("Place {}", object.kind_name()),
+            Edit::Delete { object, .. } => format!("Delete {}", object.kind_name()),
+            Edit::Modify { before, after, .. } => {
+                if before.position() != after.position() {
+                    format!("Move {}", after.kind_name())
+                } else {
+                    format!("Edit {}", after.kind_name())
+                }
+            }
+            Edit::Clear { .. } => "Clear scene".into(),
+            Edit::Group { name, .. } => (*name).into(),
+        }
+    }
+
+    /// Applies the edit (or reverts it, if `undo`) to `scene_objects`, returning the ids of every
+    /// object whose entity needs to be respawned.
+    fn apply(&self, scene_objects: &mut SceneObjects, undo: bool) -> Vec<u32> {
+        match self {
+            Edit::Spawn { id, object } | Edit::Delete { id, object } => {
+                if undo == matches!(self, Edit::Spawn { .. }) {
+                    scene_objects.objects.remove(id);
+                } else {
+                    restore(scene_objects, *id, object);
+                }
+                vec![*id]
+            }
+            Edit::Modify { id, before, after } => {
+                let object = if undo { before } else { after };
+                restore(scene_objects, *id, object);
+                vec![*id]
+            }
+            Edit::Clear { objects, object_count } => {
+                if undo {
+                    scene_objects.objects.extend(objects.clone());
+                    scene_objects.object_count = scene_objects.object_count.max(*object_count);
+                } else {
+                    // The count is left alone, so objects placed afterwards can't take the ids of
+                    // ones an undo might bring back.
+                    for id in objects.keys() {
+                        scene_objects.objects.remove(id);
+                    }
+                }
+                objects.keys().copied().collect()
+            }
+            Edit::Group { edits, .. } => {
+                if undo {
+                    edits.iter().rev().flat_map(|edit| edit.apply(scene_objects, true)).collect()
+                } else {
+                    edits.iter().flat_map(|edit| edit.apply(scene_objects, false)).collect()
+                }
+            }
+        }
+    }
+}
+
+/// Puts an object back under its old id, keeping `object_count` above every id in use so new
+/// objects never reuse it.
+fn restore(scene_objects: &mut SceneObjects, id: u32, object: &Object) {
+    scene_objects.objects.insert(id, object.clone());
+    scene_objects.object_count = scene_objects.object_count.max(id + 1);
+}
+
+#[derive(Resource, Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl History {
+    /// Records an edit that has already been made to the scene.
+    pub fn record(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.undo.push(edit);
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Edits that can be undone, oldest first.
+    pub fn done(&self) -> &[Edit] {
+        &self.undo
+    }
+
+    /// Edits that can be redone, next first.
+    pub fn undone(&self) -> impl Iterator<Item = &Edit> {
+        self.redo.iter().rev()
+    }
+}
+
+/// The side panel's undo history.
+#[derive(SystemParam)]
+pub struct HistoryUi<'w> {
+    history: Res<'w, History>,
+    undo_writer: EventWriter<'w, Undo>,
+    redo_writer: EventWriter<'w, Redo>,
+}
+
+impl HistoryUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!self.history.done().is_empty(), egui::Button::new("Undo")).clicked() {
+                self.undo_writer.send(Undo);
+            }
+            if ui.add_enabled(self.history.undone().next().is_some(), egui::Button::new("Redo")).clicked() {
+                self.redo_writer.send(Redo);
+            }
+        });
+        egui::ScrollArea::vertical().max_height(200.).show(ui, |ui| {
+            // Clicking an entry undoes or redoes everything up to and including it.
+            let done = self.history.done().len();
+            if ui.selectable_label(done == 0, "(empty scene)").clicked() {
+                self.undo_writer.send_batch((0..done).map(|_| Undo));
+            }
+            for (i, edit) in self.history.done().iter().enumerate() {
+                if ui.selectable_label(i + 1 == done, edit.describe()).clicked() {
+                    self.undo_writer.send_batch((i + 1..done).map(|_| Undo));
+                }
+            }
+            for (i, edit) in self.history.undone().enumerate() {
+                let label = egui::RichText::new(edit.describe()).weak();
+                if ui.selectable_label(false, label).clicked() {
+                    self.redo_writer.send_batch((0..=i).map(|_| Redo));
+                }
+            }
+        });
+    }
+}
+
+#[derive(Event)]
+pub struct Undo;
+
+#[derive(Event)]
+pub struct Redo;
+
+/// Makes an edit to the scene and records it in the history.
+#[derive(Event)]
+pub struct ApplyEdit(pub Edit);
+
+fn undo_shortcuts(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    mut undo_writer: EventWriter<Undo>,
+    mut redo_writer: EventWriter<Redo>,
+) {
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+    if !(keys.pressed(KeyCode::ControlLeft) || keys.pressed(KeyCode::ControlRight)) {
+        return;
+    }
+    let shift = keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight);
+    if keys.just_pressed(KeyCode::KeyZ) {
+        if shift {
+            redo_writer.send(Redo);
+        } else {
+            undo_writer.send(Undo);
+        }
+    }
+    if keys.just_pressed(KeyCode::KeyY) {
+        redo_writer.send(Redo);
+    }
+}
+
+fn record_added_objects(mut added_events: EventReader<ObjectAdded>, mut history: ResMut<History>) {
+    for ObjectAdded { id, object } in added_events.read() {
+        history.record(Edit::Spawn { id: *id, object: object.clone() });
+    }
+}
+
+fn apply_history_events(
+    mut undo_events: EventReader<Undo>,
+    mut redo_events: EventReader<Redo>,
+    mut apply_events: EventReader<ApplyEdit>,
+    mut history: ResMut<History>,
+    mut scene_objects: ResMut<SceneObjects>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
+    mut despawn_event_writer: EventWriter<DespawnObject>,
+) {
+    let mut touched = BTreeSet::new();
+    for _ in undo_events.read() {
+        if let Some(edit) = history.undo.pop() {
+            touched.extend(edit.apply(&mut scene_objects, true));
+            history.redo.push(edit);
+        }
+    }
+    for _ in redo_events.read() {
+        if let Some(edit) = history.redo.pop() {
+            touched.extend(edit.apply(&mut scene_objects, false));
+            history.undo.push(edit);
+        }
+    }
+    for ApplyEdit(edit) in apply_events.read() {
+        touched.extend(edit.apply(&mut scene_objects, false));
+        history.record(edit.clone());
+    }
+    // Several edits to the same object can be undone at once, so entities are only rebuilt from
+    // the final state of the scene.
+    for id in touched {
+        despawn_event_writer.send(DespawnObject(id));
+        if let Some(object) = scene_objects.objects.get(&id) {
+            spawn_event_writer.send(SpawnObject(object.clone(), Some(id)));
+        }
+    }
+}
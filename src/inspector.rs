@@ -0,0 +1,204 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use crate::bars::bar_editor;
+use crate::drums::Drum;
+use crate::history::{ApplyEdit, Edit};
+use crate::instruments::{InstrumentId, Instruments};
+use crate::pegs::{drum_picker, instrument_picker, SelectedObject};
+use crate::sequences::{play_mode_editor, PlayMode};
+use crate::spawners::{spawn_pattern_editor, SpawnPattern};
+use crate::simulation::{Object, Pitch, SceneObjects};
+use crate::sound::PreviewNotes;
+use crate::synth::{SynthPatch, Voice, Waveform};
+use crate::walls::wall_editor;
+
+/// Changes made in the inspector are kept here until they are applied as one undoable edit.
+#[derive(Resource, Default)]
+pub struct InspectorDraft {
+    /// The object the draft was filled in from. If it changes (e.g. through undo), the draft is
+    /// refilled.
+    source: Option<(u32, Object)>,
+    notes_text: String,
+    voice: Voice,
+    instrument: InstrumentId,
+    mode: PlayMode,
+    drum: Drum,
+    pattern: SpawnPattern,
+    restitution: f32,
+    friction: f32,
+    length: f32,
+    angle: f32,
+    split: bool,
+}
+
+/// The side panel's editor for the selected object.
+#[derive(SystemParam)]
+pub struct Inspector<'w> {
+    selected_object: Res<'w, SelectedObject>,
+    scene_objects: Res<'w, SceneObjects>,
+    instruments: Res<'w, Instruments>,
+    draft: ResMut<'w, InspectorDraft>,
+    apply_edit_writer: EventWriter<'w, ApplyEdit>,
+    preview_writer: EventWriter<'w, PreviewNotes>,
+}
+
+impl Inspector<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let Some((id, object)) = self
+            .selected_object
+            .0
+            .and_then(|id| self.scene_objects.objects.get(&id).map(|object| (id, object.clone())))
+        else {
+            self.draft.source = None;
+            return;
+        };
+        ui.collapsing("Selected", |ui| {
+            ui.label(format!("{} {}", object.kind_name(), id));
+            if let Some((notes, voice, instrument, mode)) = object.sound() {
+                if self.draft.source.as_ref() != Some(&(id, object.clone())) {
+                    self.draft.source = Some((id, object.clone()));
+                    self.draft.notes_text = notes.iter().map(|note| note.to_string()).collect::<Vec<_>>().join(", ");
+                    self.draft.voice = voice.clone();
+                    self.draft.instrument = instrument.clone();
+                    self.draft.mode = mode.clone();
+                    if let Object::Bar { length, angle, split, .. } = object {
+                        (self.draft.length, self.draft.angle, self.draft.split) = (length, angle, split);
+                    }
+                }
+                if let Object::Bar { .. } = object {
+                    let draft = &mut *self.draft;
+                    bar_editor(ui, &mut draft.length, &mut draft.angle, &mut draft.split);
+                }
+                ui.label("Notes, as names or MIDI numbers (e.g. C4, Eb4, 67)");
+                ui.text_edit_singleline(&mut self.draft.notes_text);
+                voice_editor(ui, &mut self.draft.voice);
+                if self.draft.voice == Voice::Samples {
+                    instrument_picker(ui, "Instrument", &self.instruments, &mut self.draft.instrument);
+                }
+                play_mode_editor(ui, &mut self.draft.mode);
+                let Some(new_notes) = parse_notes(&self.draft.notes_text) else {
+                    ui.colored_label(egui::Color32::RED, "Enter one or more notes between C-1 and G9");
+                    return;
+                };
+                ui.horizontal(|ui| {
+                    if ui.button("Preview").clicked() {
+                        self.preview_writer.send(PreviewNotes {
+                            notes: new_notes.clone(),
+                            voice: self.draft.voice.clone(),
+                            instrument: self.draft.instrument.clone(),
+                        });
+                    }
+                    let mut after = object.clone();
+                    if let Some((notes, voice, instrument, mode)) = after.sound_mut() {
+                        *notes = new_notes;
+                        *voice = self.draft.voice.clone();
+                        *instrument = self.draft.instrument.clone();
+                        *mode = self.draft.mode.clone();
+                    }
+                    if let Object::Bar { length, angle, split, .. } = &mut after {
+                        (*length, *angle, *split) = (self.draft.length, self.draft.angle, self.draft.split);
+                    }
+                    if ui.add_enabled(after != object, egui::Button::new("Apply")).clicked() {
+                        self.apply_edit_writer.send(ApplyEdit(Edit::Modify { id, before: object.clone(), after }));
+                    }
+                });
+            } else if let Object::Drum { x, y, ref sound } = object {
+                if self.draft.source.as_ref() != Some(&(id, object.clone())) {
+                    self.draft.source = Some((id, object.clone()));
+                    self.draft.drum = sound.clone();
+                }
+                drum_picker(ui, "Drum", &mut self.draft.drum);
+                if matches!(&self.draft.drum, Drum::Custom(path) if path.is_empty()) {
+                    ui.colored_label(egui::Color32::RED, "Enter the sample's path");
+                    return;
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Preview").clicked() {
+                        self.preview_writer.send(PreviewNotes {
+                            notes: vec![Pitch(self.draft.drum.midi_note())],
+                            voice: Voice::Drum(self.draft.drum.clone()),
+                            instrument: InstrumentId::default(),
+                        });
+                    }
+                    if ui.add_enabled(self.draft.drum != *sound, egui::Button::new("Apply")).clicked() {
+                        self.apply_edit_writer.send(ApplyEdit(Edit::Modify {
+                            id,
+                            before: object.clone(),
+                            after: Object::Drum { x, y, sound: self.draft.drum.clone() },
+                        }));
+                    }
+                });
+            } else if let Object::BallSpawner { x, y, ref pattern } = object {
+                if self.draft.source.as_ref() != Some(&(id, object.clone())) {
+                    self.draft.source = Some((id, object.clone()));
+                    self.draft.pattern = pattern.clone();
+                }
+                spawn_pattern_editor(ui, &mut self.draft.pattern);
+                if ui.add_enabled(self.draft.pattern != *pattern, egui::Button::new("Apply")).clicked() {
+                    self.apply_edit_writer.send(ApplyEdit(Edit::Modify {
+                        id,
+                        before: object.clone(),
+                        after: Object::BallSpawner { x, y, pattern: self.draft.pattern.clone() },
+                    }));
+                }
+            } else if let Object::Wall { ref points, restitution, friction } = object {
+                if self.draft.source.as_ref() != Some(&(id, object.clone())) {
+                    self.draft.source = Some((id, object.clone()));
+                    self.draft.restitution = restitution;
+                    self.draft.friction = friction;
+                }
+                let draft = &mut *self.draft;
+                wall_editor(ui, &mut draft.restitution, &mut draft.friction);
+                let changed = self.draft.restitution != restitution || self.draft.friction != friction;
+                if ui.add_enabled(changed, egui::Button::new("Apply")).clicked() {
+                    self.apply_edit_writer.send(ApplyEdit(Edit::Modify {
+                        id,
+                        before: object.clone(),
+                        after: Object::Wall {
+                            points: points.clone(),
+                            restitution: self.draft.restitution,
+                            friction: self.draft.friction,
+                        },
+                    }));
+                }
+            }
+        });
+    }
+}
+
+fn voice_editor(ui: &mut egui::Ui, voice: &mut Voice) {
+    egui::ComboBox::from_label("Sound")
+        .selected_text(voice.name())
+        .show_ui(ui, |ui| {
+            if ui.selectable_label(*voice == Voice::Samples, Voice::Samples.name()).clicked() {
+                *voice = Voice::Samples;
+            }
+            if ui.selectable_label(matches!(voice, Voice::Synth(_)), "Synth").clicked() && !matches!(voice, Voice::Synth(_)) {
+                *voice = Voice::Synth(SynthPatch::default());
+            }
+        });
+    let Voice::Synth(patch) = voice else { return };
+    egui::ComboBox::from_label("Waveform")
+        .selected_text(format!("{:?}", patch.waveform))
+        .show_ui(ui, |ui| {
+            for waveform in Waveform::ALL {
+                ui.selectable_value(&mut patch.waveform, waveform, format!("{:?}", waveform));
+            }
+        });
+    ui.add(egui::Slider::new(&mut patch.attack, 0.001..=2.).logarithmic(true).text("Attack").suffix(" s"));
+    ui.add(egui::Slider::new(&mut patch.decay, 0.001..=2.).logarithmic(true).text("Decay").suffix(" s"));
+    ui.add(egui::Slider::new(&mut patch.sustain, 0.0..=1.).text("Sustain"));
+    ui.add(egui::Slider::new(&mut patch.release, 0.01..=4.).logarithmic(true).text("Release").suffix(" s"));
+    ui.add(egui::Slider::new(&mut patch.length, 0.01..=2.).logarithmic(true).text("Length").suffix(" s"));
+    ui.add(egui::Slider::new(&mut patch.cutoff, 50.0..=7000.).logarithmic(true).text("Cutoff").suffix(" Hz"));
+    ui.add(egui::Slider::new(&mut patch.resonance, 0.0..=0.95).text("Resonance"));
+}
+
+fn parse_notes(text: &str) -> Option<Vec<Pitch>> {
+    let notes = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<Pitch>>>()?;
+    (!notes.is_empty()).then_some(notes)
+}
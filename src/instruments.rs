@@ -0,0 +1,123 @@
+use bevy::{asset::io::file::FileAssetReader, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::simulation::Pitch;
+
+/// Finds the sample packs in `assets/sounds` when the app starts.
+pub struct InstrumentPlugin;
+
+impl Plugin for InstrumentPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Instruments::discover());
+    }
+}
+
+/// Every instrument folder has one of these.
+const MANIFEST: &str = "instrument.ron";
+
+/// The folder name of a sample pack under `assets/sounds`.
+#[derive(Component, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InstrumentId(pub String);
+
+impl Default for InstrumentId {
+    /// The pack horizons ships with, which every scene used before instruments existed.
+    fn default() -> Self {
+        InstrumentId("default".into())
+    }
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    name: String,
+    /// Note names or MIDI numbers, mapped to file names in the instrument's folder.
+    notes: BTreeMap<String, String>,
+}
+
+pub struct Instrument {
+    pub name: String,
+    /// Asset paths of the recordings, by the note they were recorded at.
+    recordings: BTreeMap<Pitch, String>,
+}
+
+impl Instrument {
+    fn load(folder: &Path, id: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(folder.join(MANIFEST)).map_err(|e| e.to_string())?;
+        let manifest: Manifest = ron::from_str(&text).map_err(|e| e.to_string())?;
+        let mut recordings = BTreeMap::new();
+        for (note, file) in manifest.notes {
+            let pitch = note.parse().map_err(|_| format!("`{}` is not a note", note))?;
+            if !folder.join(&file).is_file() {
+                return Err(format!("{} is missing", file));
+            }
+            recordings.insert(pitch, format!("sounds/{}/{}", id, file));
+        }
+        if recordings.is_empty() {
+            return Err("no notes are listed".into());
+        }
+        Ok(Instrument { name: manifest.name, recordings })
+    }
+
+    /// The asset path of the closest recording, and the playback speed that shifts it to
+    /// `frequency`. Recordings are named by their 12-TET note; on a tie the lower one is used.
+    pub fn nearest_recording(&self, frequency: f32) -> (&str, f32) {
+        let semitones = |recorded: &Pitch| (12. * (frequency / recorded.frequency()).log2()).abs();
+        let (recorded, path) = self
+            .recordings
+            .iter()
+            .reduce(|nearest, recording| {
+                if semitones(recording.0) < semitones(nearest.0) - 1e-3 { recording } else { nearest }
+            })
+            .expect("instruments always have a recording");
+        (path, frequency / recorded.frequency())
+    }
+}
+
+/// The folder Bevy loads assets from.
+pub fn assets_folder() -> PathBuf {
+    FileAssetReader::get_base_path().join("assets")
+}
+
+#[derive(Resource)]
+pub struct Instruments(BTreeMap<InstrumentId, Instrument>);
+
+impl Instruments {
+    /// Loads every sample pack's manifest. Broken packs are skipped with a warning.
+    pub fn discover() -> Self {
+        let mut instruments = BTreeMap::new();
+        let sounds = assets_folder().join("sounds");
+        let folders = match fs::read_dir(&sounds) {
+            Ok(folders) => folders,
+            Err(e) => {
+                warn!("could not read {}: {}", sounds.display(), e);
+                return Instruments(instruments);
+            }
+        };
+        for folder in folders.flatten().map(|entry| entry.path()).filter(|path| path.join(MANIFEST).is_file()) {
+            let Some(id) = folder.file_name().and_then(|name| name.to_str()).map(str::to_owned) else { continue };
+            match Instrument::load(&folder, &id) {
+                Ok(instrument) => {
+                    instruments.insert(InstrumentId(id), instrument);
+                }
+                Err(e) => warn!("skipping instrument {}: {}", id, e),
+            }
+        }
+        Instruments(instruments)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&InstrumentId, &Instrument)> {
+        self.0.iter()
+    }
+
+    pub fn name<'a>(&'a self, id: &'a InstrumentId) -> &'a str {
+        self.0.get(id).map_or(&id.0, |instrument| &instrument.name)
+    }
+
+    /// The instrument a peg should play with. Scenes can refer to packs that aren't installed;
+    /// those pegs fall back to the default pack.
+    pub fn get(&self, id: &InstrumentId) -> Option<&Instrument> {
+        self.0.get(id).or_else(|| self.0.get(&InstrumentId::default()))
+    }
+}
@@ -1,40 +1,97 @@
+// Bevy systems routinely take many parameters and complex query filters.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
 use bevy::prelude::*;
 use bevy_egui::EguiPlugin;
-use bevy_rapier2d::prelude::*;
 use bevy_file_dialog::prelude::*;
 
+mod autosave;
+mod bars;
 mod camera;
+mod chords;
+mod cli;
+mod drums;
+mod dynamics;
+mod history;
+mod inspector;
+mod instruments;
+mod midi;
+mod midi_input;
+mod midi_output;
+mod mixdown;
 mod pegs;
+mod scales;
+mod scene;
+mod sequences;
+mod simulation;
+mod spatial;
+mod sound;
+mod spawners;
+mod synth;
+mod transport;
+mod tuning;
 mod ui;
+mod voices;
+mod walls;
 
+use autosave::AutosavePlugin;
+use bars::BarPlugin;
 use camera::CameraPlugin;
+use drums::DrumPlugin;
+use history::HistoryPlugin;
+use instruments::InstrumentPlugin;
+use midi::MidiPlugin;
+use midi_input::MidiInputPlugin;
+use midi_output::MidiOutputPlugin;
 use pegs::PegPlugin;
-use ui::UiPlugin;
+use simulation::SimulationPlugin;
+use sound::SoundPlugin;
+use spatial::SpatialAudioPlugin;
+use synth::SynthPlugin;
+use tuning::{KeymapFileContents, ScalaFileContents, TuningPlugin};
+use ui::{SceneFileContents, UiPlugin};
+use voices::VoicePlugin;
+use walls::WallPlugin;
 
 pub struct TextFileContents;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     App::new()
         .add_plugins(DefaultPlugins.set(ImagePlugin::default_nearest()))
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
+        .add_plugins(SimulationPlugin)
         // .add_plugins(RapierDebugRenderPlugin::default())
         .add_plugins(
             FileDialogPlugin::new()
                 // allow saving of files marked with TextFileContents
                 .with_save_file::<TextFileContents>()
+                .with_save_file::<SceneFileContents>()
                 // allow loading of files marked with TextFileContents
-                .with_load_file::<TextFileContents>(),
+                .with_load_file::<TextFileContents>()
+                .with_load_file::<ScalaFileContents>()
+                .with_load_file::<KeymapFileContents>(),
         )
         .add_plugins(EguiPlugin)
         .add_plugins(CameraPlugin)
         .add_plugins(PegPlugin)
+        .add_plugins(WallPlugin)
+        .add_plugins(BarPlugin)
+        .add_plugins(InstrumentPlugin)
+        .add_plugins(SynthPlugin)
+        .add_plugins(DrumPlugin)
+        .add_plugins(SoundPlugin)
+        .add_plugins(TuningPlugin)
+        .add_plugins(SpatialAudioPlugin)
+        .add_plugins(VoicePlugin)
+        .add_plugins(MidiPlugin)
+        .add_plugins(MidiOutputPlugin)
+        .add_plugins(MidiInputPlugin)
+        .add_plugins(HistoryPlugin)
         .add_plugins(UiPlugin)
-        .add_systems(Startup, setup)
+        .add_plugins(AutosavePlugin)
         .run();
 }
-
-fn setup(
-    mut rapier_config: ResMut<RapierConfiguration>,
-) {
-    rapier_config.timestep_mode = TimestepMode::Fixed { dt: 1./64., substeps: 1 };
-}
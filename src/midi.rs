@@ -0,0 +1,165 @@
+use bevy::prelude::*;
+use std::collections::BTreeMap;
+use crate::simulation::{NoteEvent, StartPerformance};
+use crate::synth::Voice;
+use crate::transport::TimeSignature;
+
+/// Keeps the notes of the most recent performance so they can be exported as MIDI.
+pub struct MidiPlugin;
+
+impl Plugin for MidiPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Recording>()
+            .add_systems(FixedUpdate, record_notes);
+    }
+}
+
+/// MIDI ticks per quarter note.
+const DIVISION: u16 = 480;
+
+/// Every note is written as an eighth note; the samples ring out on their own, so the length
+/// only matters for how the notes look in a DAW.
+const NOTE_LENGTH: u32 = DIVISION as u32 / 2;
+
+/// Bounce speed, in pixels per second, that maps to the loudest MIDI velocity.
+const FULL_VELOCITY_SPEED: f32 = 400.;
+
+/// Channel 10, where General MIDI devices play percussion.
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// Converts the speed a ball bounced off a peg with into a MIDI velocity.
+pub fn velocity(speed: f32) -> u8 {
+    (speed / FULL_VELOCITY_SPEED * 127.).round().clamp(1., 127.) as u8
+}
+
+#[derive(Clone, Copy)]
+pub struct RecordedNote {
+    /// Seconds since the performance started.
+    pub time: f32,
+    pub note: u8,
+    pub velocity: u8,
+    pub spawner: Option<u32>,
+    /// `DRUM_CHANNEL` for percussion pegs, otherwise the first channel.
+    pub channel: u8,
+}
+
+impl RecordedNote {
+    pub fn from_event(time: f32, note_event: &NoteEvent) -> impl Iterator<Item = RecordedNote> + '_ {
+        note_event.notes.iter().map(move |note| RecordedNote {
+            time,
+            note: note.0,
+            velocity: velocity(note_event.speed),
+            spawner: note_event.spawner,
+            channel: if matches!(note_event.voice, Voice::Drum(_)) { DRUM_CHANNEL } else { 0 },
+        })
+    }
+}
+
+/// The notes played since Start was last pressed.
+#[derive(Resource, Default)]
+pub struct Recording {
+    started_at: f32,
+    pub notes: Vec<RecordedNote>,
+}
+
+fn record_notes(
+    time: Res<Time>,
+    mut start_events: EventReader<StartPerformance>,
+    mut note_events: EventReader<NoteEvent>,
+    mut recording: ResMut<Recording>,
+) {
+    for _ in start_events.read() {
+        recording.started_at = time.elapsed_seconds();
+        recording.notes.clear();
+    }
+    let now = time.elapsed_seconds() - recording.started_at;
+    for note_event in note_events.read() {
+        recording.notes.extend(RecordedNote::from_event(now, note_event));
+    }
+}
+
+/// Writes `notes` as a format 1 Standard MIDI File with one track per spawner, plus one for
+/// balls placed by hand.
+pub fn write_smf(notes: &[RecordedNote], tempo: f32, time_signature: TimeSignature, title: &str) -> Vec<u8> {
+    let mut tracks: BTreeMap<Option<u32>, Vec<RecordedNote>> = BTreeMap::new();
+    for note in notes {
+        tracks.entry(note.spawner).or_default().push(*note);
+    }
+
+    let mut conductor = Vec::new();
+    if !title.is_empty() {
+        meta_event(&mut conductor, 0, 0x03, title.as_bytes());
+    }
+    // MIDI counts tempo in quarter notes, while the scene counts it in beats of the time signature.
+    let TimeSignature { beats, unit } = time_signature;
+    let tempo = tempo * 4. / unit as f32;
+    let microseconds_per_quarter = (60_000_000. / tempo).round() as u32;
+    meta_event(&mut conductor, 0, 0x51, &microseconds_per_quarter.to_be_bytes()[1..]);
+    // The denominator is stored as a power of two, with a click every quarter note and 8 32nd
+    // notes to the quarter.
+    meta_event(&mut conductor, 0, 0x58, &[beats as u8, unit.trailing_zeros() as u8, 24, 8]);
+    meta_event(&mut conductor, 0, 0x2f, &[]);
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&(tracks.len() as u16 + 1).to_be_bytes());
+    bytes.extend_from_slice(&DIVISION.to_be_bytes());
+    write_chunk(&mut bytes, &conductor);
+    for (spawner, notes) in tracks {
+        let name = match spawner {
+            Some(id) => format!("Spawner {}", id),
+            None => "Placed balls".into(),
+        };
+        write_chunk(&mut bytes, &note_track(&name, &notes, tempo));
+    }
+    bytes
+}
+
+fn note_track(name: &str, notes: &[RecordedNote], tempo: f32) -> Vec<u8> {
+    // (tick, note on?, channel, note, velocity), with note offs sorted before note ons on the same
+    // tick so a repeated note isn't cut short by the previous one ending.
+    let mut events = Vec::new();
+    for note in notes {
+        let tick = (note.time * tempo / 60. * DIVISION as f32).round() as u32;
+        events.push((tick, true, note.channel, note.note, note.velocity));
+        events.push((tick + NOTE_LENGTH, false, note.channel, note.note, 0));
+    }
+    events.sort_by_key(|&(tick, on, channel, note, _)| (tick, on, channel, note));
+
+    let mut track = Vec::new();
+    meta_event(&mut track, 0, 0x03, name.as_bytes());
+    let mut last_tick = 0;
+    for (tick, on, channel, note, velocity) in events {
+        write_variable_length(&mut track, tick - last_tick);
+        track.extend_from_slice(&[if on { 0x90 } else { 0x80 } | channel, note, velocity]);
+        last_tick = tick;
+    }
+    meta_event(&mut track, 0, 0x2f, &[]);
+    track
+}
+
+fn meta_event(track: &mut Vec<u8>, delta: u32, kind: u8, data: &[u8]) {
+    write_variable_length(track, delta);
+    track.extend_from_slice(&[0xff, kind]);
+    write_variable_length(track, data.len() as u32);
+    track.extend_from_slice(data);
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, track: &[u8]) {
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(track);
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
@@ -0,0 +1,193 @@
+use bevy::{ecs::system::SystemParam, prelude::*, window::PrimaryWindow};
+use bevy_egui::egui;
+use midir::{MidiInput, MidiInputConnection};
+use std::collections::BTreeSet;
+use std::sync::mpsc::{self, Receiver};
+use crate::camera::MainCamera;
+use crate::history::{ApplyEdit, Edit};
+use crate::pegs::{NewPegSettings, SelectedObject};
+use crate::simulation::{Pitch, SceneObjects, SpawnObject};
+
+/// Places and retunes pegs from a MIDI keyboard.
+pub struct MidiInputPlugin;
+
+impl Plugin for MidiInputPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(MidiInputSettings {
+                ports: list_ports(),
+                port: None,
+                error: None,
+            })
+            .insert_non_send_resource(MidiKeyboard {
+                connection: None,
+                held: BTreeSet::new(),
+                chord: BTreeSet::new(),
+            })
+            .add_event::<ConnectMidiInput>()
+            .add_event::<RefreshMidiInputPorts>()
+            .add_systems(Update, (refresh_ports, connect_input))
+            .add_systems(FixedUpdate, play_keys);
+    }
+}
+
+const CLIENT_NAME: &str = "horizons";
+
+#[derive(Resource)]
+struct MidiInputSettings {
+    /// Names of the input ports found by the last refresh.
+    ports: Vec<String>,
+    port: Option<String>,
+    error: Option<String>,
+}
+
+/// Connects to a MIDI input port, or disconnects if `None`.
+#[derive(Event)]
+struct ConnectMidiInput(Option<String>);
+
+#[derive(Event)]
+struct RefreshMidiInputPorts;
+
+/// midir delivers messages on its own thread; they are passed to the app through a channel.
+struct MidiKeyboard {
+    connection: Option<(MidiInputConnection<()>, Receiver<Vec<u8>>)>,
+    /// Keys currently held down.
+    held: BTreeSet<u8>,
+    /// Every key pressed since the last time no keys were held.
+    chord: BTreeSet<Pitch>,
+}
+
+/// The side panel's MIDI input settings.
+#[derive(SystemParam)]
+pub struct MidiInputUi<'w> {
+    settings: ResMut<'w, MidiInputSettings>,
+    connect_writer: EventWriter<'w, ConnectMidiInput>,
+    refresh_writer: EventWriter<'w, RefreshMidiInputPorts>,
+}
+
+impl MidiInputUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let mut port = self.settings.port.clone();
+        egui::ComboBox::from_label("Keyboard")
+            .selected_text(port.as_deref().unwrap_or("Off"))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut port, None, "Off");
+                for name in &self.settings.ports {
+                    ui.selectable_value(&mut port, Some(name.clone()), name);
+                }
+            });
+        if port != self.settings.port {
+            self.connect_writer.send(ConnectMidiInput(port));
+        }
+        if ui.button("Refresh keyboards").clicked() {
+            self.refresh_writer.send(RefreshMidiInputPorts);
+        }
+        ui.label("Play a note or chord to place a peg at the cursor, or to retune the selected peg.");
+        ui.weak("A chord is placed once every key has been released, so it can be built up a key at a time.");
+        if let Some(error) = &self.settings.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    }
+}
+
+fn list_ports() -> Vec<String> {
+    let Ok(input) = MidiInput::new(CLIENT_NAME) else { return Vec::new() };
+    input.ports().iter().filter_map(|port| input.port_name(port).ok()).collect()
+}
+
+fn refresh_ports(mut refresh_events: EventReader<RefreshMidiInputPorts>, mut settings: ResMut<MidiInputSettings>) {
+    if refresh_events.read().count() > 0 {
+        settings.ports = list_ports();
+    }
+}
+
+fn open(name: &str) -> Result<(MidiInputConnection<()>, Receiver<Vec<u8>>), String> {
+    let input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    let port = input
+        .ports()
+        .into_iter()
+        .find(|port| input.port_name(port).as_deref() == Ok(name))
+        .ok_or_else(|| format!("MIDI port {} is gone", name))?;
+    let (sender, receiver) = mpsc::channel();
+    let connection = input
+        .connect(&port, CLIENT_NAME, move |_, message, _| {
+            let _ = sender.send(message.to_vec());
+        }, ())
+        .map_err(|e| e.to_string())?;
+    Ok((connection, receiver))
+}
+
+fn connect_input(
+    mut connect_events: EventReader<ConnectMidiInput>,
+    mut settings: ResMut<MidiInputSettings>,
+    mut keyboard: NonSendMut<MidiKeyboard>,
+) {
+    for ConnectMidiInput(port) in connect_events.read() {
+        if let Some((connection, _)) = keyboard.connection.take() {
+            connection.close();
+        }
+        keyboard.held.clear();
+        keyboard.chord.clear();
+        settings.port = None;
+        settings.error = None;
+        let Some(port) = port else { continue };
+        match open(port) {
+            Ok(connection) => {
+                keyboard.connection = Some(connection);
+                settings.port = Some(port.clone());
+            }
+            Err(e) => settings.error = Some(format!("could not open {}: {}", port, e)),
+        }
+    }
+}
+
+/// Collects keys until every key has been released, then turns them into a peg's notes.
+fn play_keys(
+    mut keyboard: NonSendMut<MidiKeyboard>,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    selected_object: Res<SelectedObject>,
+    scene_objects: Res<SceneObjects>,
+    new_peg_settings: Res<NewPegSettings>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
+    mut apply_edit_writer: EventWriter<ApplyEdit>,
+) {
+    let MidiKeyboard { connection, held, chord } = &mut *keyboard;
+    let Some((_, receiver)) = connection else { return };
+    for message in receiver.try_iter() {
+        match *message.as_slice() {
+            [status, key, velocity] if status & 0xf0 == 0x90 && velocity > 0 => {
+                held.insert(key);
+                chord.insert(Pitch(key));
+            }
+            // A Note On with zero velocity is a Note Off.
+            [status, key, _] if status & 0xf0 == 0x80 || status & 0xf0 == 0x90 => {
+                held.remove(&key);
+            }
+            _ => {}
+        }
+    }
+    if !held.is_empty() || chord.is_empty() {
+        return;
+    }
+
+    let notes: Vec<Pitch> = std::mem::take(chord).into_iter().collect();
+    let selected_peg = selected_object.0.and_then(|id| scene_objects.objects.get(&id).map(|object| (id, object)));
+    if let Some((id, before)) = selected_peg {
+        let mut after = before.clone();
+        if let Some((peg_notes, ..)) = after.sound_mut() {
+            *peg_notes = notes;
+            apply_edit_writer.send(ApplyEdit(Edit::Modify { id, before: before.clone(), after }));
+            return;
+        }
+    }
+    let (camera, camera_transform) = primary_camera.single();
+    if let Some(position) = primary_window
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+    {
+        spawn_event_writer.send(SpawnObject(new_peg_settings.peg(position, notes), None));
+    }
+}
@@ -0,0 +1,240 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::time::Duration;
+use crate::midi::{velocity, DRUM_CHANNEL};
+use crate::simulation::{NoteEvent, ObjectId, Pitch, ResetPerformance};
+use crate::synth::Voice;
+
+/// Sends peg hits to a MIDI port as they happen, alongside the bundled samples.
+pub struct MidiOutputPlugin;
+
+impl Plugin for MidiOutputPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(MidiOutputSettings {
+                ports: list_ports(),
+                target: None,
+                channels: ChannelMode::PerPeg,
+                error: None,
+            })
+            .insert_non_send_resource(MidiConnection { connection: None, sounding: Vec::new() })
+            .add_event::<ConnectMidiOutput>()
+            .add_event::<RefreshMidiPorts>()
+            .add_systems(Update, (refresh_ports, connect_output, release_notes))
+            .add_systems(FixedUpdate, send_notes);
+    }
+}
+
+const CLIENT_NAME: &str = "horizons";
+
+/// How long each note is held before its Note Off is sent.
+const NOTE_LENGTH: Duration = Duration::from_millis(250);
+
+#[derive(Clone, PartialEq)]
+enum MidiTarget {
+    /// A port other programs can connect to (ALSA or CoreMIDI only).
+    Virtual,
+    Port(String),
+}
+
+impl MidiTarget {
+    fn name(&self) -> &str {
+        match self {
+            MidiTarget::Virtual => "Virtual port",
+            MidiTarget::Port(name) => name,
+        }
+    }
+}
+
+/// How melodic notes are spread over channels. Percussion pegs always send on channel 10, which
+/// melodic notes skip.
+#[derive(Clone, Copy, PartialEq)]
+enum ChannelMode {
+    /// Each peg takes a channel by its id, going round the 15 melodic channels.
+    PerPeg,
+    /// Balls from a spawner take a channel by the spawner's id, so they stay on the same channel
+    /// from one performance to the next. Balls placed by hand send on channel 1.
+    PerBall,
+}
+
+/// The `index`th melodic channel, going round the 15 channels other than `DRUM_CHANNEL`.
+fn melodic_channel(index: u32) -> u8 {
+    let channel = (index % 15) as u8;
+    if channel >= DRUM_CHANNEL { channel + 1 } else { channel }
+}
+
+#[derive(Resource)]
+struct MidiOutputSettings {
+    /// Names of the output ports found by the last refresh.
+    ports: Vec<String>,
+    /// Where notes are being sent, if anywhere.
+    target: Option<MidiTarget>,
+    channels: ChannelMode,
+    error: Option<String>,
+}
+
+/// Connects to a MIDI output, or disconnects if `None`.
+#[derive(Event)]
+struct ConnectMidiOutput(Option<MidiTarget>);
+
+#[derive(Event)]
+struct RefreshMidiPorts;
+
+/// The side panel's MIDI output settings.
+#[derive(SystemParam)]
+pub struct MidiOutputUi<'w> {
+    settings: ResMut<'w, MidiOutputSettings>,
+    connect_writer: EventWriter<'w, ConnectMidiOutput>,
+    refresh_writer: EventWriter<'w, RefreshMidiPorts>,
+}
+
+impl MidiOutputUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let mut target = self.settings.target.clone();
+        let selected = target.as_ref().map_or("Off", |target| target.name()).to_owned();
+        egui::ComboBox::from_label("Port").selected_text(selected).show_ui(ui, |ui| {
+            ui.selectable_value(&mut target, None, "Off");
+            if cfg!(unix) {
+                ui.selectable_value(&mut target, Some(MidiTarget::Virtual), MidiTarget::Virtual.name());
+            }
+            for port in &self.settings.ports {
+                ui.selectable_value(&mut target, Some(MidiTarget::Port(port.clone())), port);
+            }
+        });
+        if target != self.settings.target {
+            self.connect_writer.send(ConnectMidiOutput(target));
+        }
+        if ui.button("Refresh ports").clicked() {
+            self.refresh_writer.send(RefreshMidiPorts);
+        }
+        ui.radio_value(&mut self.settings.channels, ChannelMode::PerPeg, "Channel per peg");
+        ui.radio_value(&mut self.settings.channels, ChannelMode::PerBall, "Channel per spawner");
+        if let Some(error) = &self.settings.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    }
+}
+
+/// midir connections aren't `Sync`, so this lives on the main thread.
+struct MidiConnection {
+    connection: Option<MidiOutputConnection>,
+    /// (when to release, channel, note) for every note that hasn't been sent a Note Off yet.
+    sounding: Vec<(Duration, u8, u8)>,
+}
+
+impl MidiConnection {
+    fn send(&mut self, message: &[u8]) {
+        if let Some(connection) = &mut self.connection {
+            if let Err(e) = connection.send(message) {
+                warn!("could not send MIDI message: {}", e);
+            }
+        }
+    }
+
+    fn release_all(&mut self) {
+        for (_, channel, note) in std::mem::take(&mut self.sounding) {
+            self.send(&[0x80 | channel, note, 0]);
+        }
+    }
+}
+
+fn list_ports() -> Vec<String> {
+    let Ok(output) = MidiOutput::new(CLIENT_NAME) else { return Vec::new() };
+    output.ports().iter().filter_map(|port| output.port_name(port).ok()).collect()
+}
+
+fn refresh_ports(mut refresh_events: EventReader<RefreshMidiPorts>, mut settings: ResMut<MidiOutputSettings>) {
+    if refresh_events.read().count() > 0 {
+        settings.ports = list_ports();
+    }
+}
+
+fn open(target: &MidiTarget) -> Result<MidiOutputConnection, String> {
+    let output = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    match target {
+        #[cfg(unix)]
+        MidiTarget::Virtual => {
+            use midir::os::unix::VirtualOutput;
+            output.create_virtual(CLIENT_NAME).map_err(|e| e.to_string())
+        }
+        #[cfg(not(unix))]
+        MidiTarget::Virtual => Err("virtual ports aren't supported on this platform".into()),
+        MidiTarget::Port(name) => {
+            let port = output
+                .ports()
+                .into_iter()
+                .find(|port| output.port_name(port).as_ref() == Ok(name))
+                .ok_or_else(|| format!("MIDI port {} is gone", name))?;
+            output.connect(&port, CLIENT_NAME).map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn connect_output(
+    mut connect_events: EventReader<ConnectMidiOutput>,
+    mut settings: ResMut<MidiOutputSettings>,
+    mut midi: NonSendMut<MidiConnection>,
+) {
+    for ConnectMidiOutput(target) in connect_events.read() {
+        midi.release_all();
+        if let Some(connection) = midi.connection.take() {
+            connection.close();
+        }
+        settings.target = None;
+        settings.error = None;
+        let Some(target) = target else { continue };
+        match open(target) {
+            Ok(connection) => {
+                midi.connection = Some(connection);
+                settings.target = Some(target.clone());
+            }
+            Err(e) => settings.error = Some(format!("could not open {}: {}", target.name(), e)),
+        }
+    }
+}
+
+fn send_notes(
+    time: Res<Time<Real>>,
+    settings: Res<MidiOutputSettings>,
+    mut note_events: EventReader<NoteEvent>,
+    mut reset_events: EventReader<ResetPerformance>,
+    peg_query: Query<&ObjectId>,
+    mut midi: NonSendMut<MidiConnection>,
+) {
+    if reset_events.read().count() > 0 {
+        midi.release_all();
+    }
+    if midi.connection.is_none() {
+        note_events.clear();
+        return;
+    }
+    for note_event in note_events.read() {
+        let channel = match (&note_event.voice, settings.channels) {
+            (Voice::Drum(_), _) => DRUM_CHANNEL,
+            (_, ChannelMode::PerPeg) => peg_query.get(note_event.peg).map_or(0, |&ObjectId(id)| melodic_channel(id)),
+            (_, ChannelMode::PerBall) => note_event.spawner.map_or(0, melodic_channel),
+        };
+        let velocity = velocity(note_event.speed);
+        for &Pitch(note) in &note_event.notes {
+            // Retriggering a note that is still held ends it first, so every Note On has its own
+            // Note Off.
+            if let Some(i) = midi.sounding.iter().position(|&(_, c, n)| (c, n) == (channel, note)) {
+                midi.sounding.remove(i);
+                midi.send(&[0x80 | channel, note, 0]);
+            }
+            midi.send(&[0x90 | channel, note, velocity]);
+            midi.sounding.push((time.elapsed() + NOTE_LENGTH, channel, note));
+        }
+    }
+}
+
+/// Runs in `Update` so held notes are still released while the simulation is paused.
+fn release_notes(time: Res<Time<Real>>, mut midi: NonSendMut<MidiConnection>) {
+    let now = time.elapsed();
+    let (done, held): (Vec<_>, Vec<_>) = std::mem::take(&mut midi.sounding).into_iter().partition(|&(end, _, _)| end <= now);
+    midi.sounding = held;
+    for (_, channel, note) in done {
+        midi.send(&[0x80 | channel, note, 0]);
+    }
+}
@@ -0,0 +1,219 @@
+use bevy::audio::Decodable;
+use lewton::inside_ogg::OggStreamReader;
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::fs::File;
+use std::path::Path;
+use crate::drums::{Drum, DrumHit};
+use crate::dynamics::Dynamics;
+use crate::spatial::SpatialAudio;
+use crate::instruments::{self, InstrumentId, Instruments};
+use crate::simulation::{NoteEvent, Pitch, TIMESTEP};
+use crate::synth::{self, SynthNote, Voice};
+use crate::tuning::Tuning;
+use crate::voices::{self, Retrigger, VoiceSettings};
+
+/// Rendered audio is always stereo at this rate; samples recorded at other rates are resampled.
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// A decoded recording as interleaved stereo frames at `SAMPLE_RATE`.
+struct Sample(Vec<[f32; 2]>);
+
+/// Every note's recording, decoded and pitch-shifted up front so the mix doesn't touch the disk.
+pub struct SampleBank {
+    samples: HashMap<(InstrumentId, Pitch), Sample>,
+    /// Custom drum samples, by asset path.
+    drums: HashMap<String, Sample>,
+}
+
+impl SampleBank {
+    /// Loads the recordings for every sampled note and custom drum in `hits` from the asset
+    /// folder, pitch-shifted to the notes' frequencies in `tuning`. Custom drums that can't be
+    /// loaded are left silent.
+    pub fn load(instruments: &Instruments, tuning: &Tuning, hits: &[(u64, NoteEvent)]) -> Result<Self, String> {
+        let assets = instruments::assets_folder();
+        let mut samples = HashMap::new();
+        let mut drums = HashMap::new();
+        for (_, note_event) in hits {
+            if let Voice::Drum(Drum::Custom(path)) = &note_event.voice {
+                if let Entry::Vacant(entry) = drums.entry(path.clone()) {
+                    // As in the editor, a drum whose sample is missing is silent rather than
+                    // stopping everything else from playing. Its empty sample is stored so it is
+                    // only reported once.
+                    entry.insert(decode_ogg(&assets.join(path), 1.).unwrap_or_else(|e| {
+                        eprintln!("skipping custom drum: {}", e);
+                        Sample(Vec::new())
+                    }));
+                }
+            }
+        }
+        for (_, note_event) in hits.iter().filter(|(_, note_event)| note_event.voice == Voice::Samples) {
+            let instrument = instruments
+                .get(&note_event.instrument)
+                .ok_or_else(|| format!("no instrument found for {}", note_event.instrument.0))?;
+            for &note in &note_event.notes {
+                let Some(frequency) = tuning.frequency(note) else { continue };
+                if let Entry::Vacant(entry) = samples.entry((note_event.instrument.clone(), note)) {
+                    let (path, speed) = instrument.nearest_recording(frequency);
+                    entry.insert(decode_ogg(&assets.join(path), speed)?);
+                }
+            }
+        }
+        Ok(SampleBank { samples, drums })
+    }
+}
+
+/// Decodes a recording, played back `speed` times faster than it was recorded.
+fn decode_ogg(path: &Path, speed: f32) -> Result<Sample, String> {
+    let error = |e: &dyn std::fmt::Display| format!("could not decode {}: {}", path.display(), e);
+    let file = File::open(path).map_err(|e| error(&e))?;
+    let mut reader = OggStreamReader::new(file).map_err(|e| error(&e))?;
+    let channels = reader.ident_hdr.audio_channels as usize;
+    let rate = reader.ident_hdr.audio_sample_rate;
+    let mut frames = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|e| error(&e))? {
+        for frame in packet.chunks_exact(channels) {
+            let left = frame[0] as f32 / i16::MAX as f32;
+            let right = frame.get(1).map_or(left, |&right| right as f32 / i16::MAX as f32);
+            frames.push([left, right]);
+        }
+    }
+    Ok(Sample(resample(&frames, rate as f64 * speed as f64)))
+}
+
+/// Linearly resamples stereo frames recorded at `rate` to `SAMPLE_RATE`.
+fn resample(frames: &[[f32; 2]], rate: f64) -> Vec<[f32; 2]> {
+    if rate == SAMPLE_RATE as f64 || frames.is_empty() {
+        return frames.to_vec();
+    }
+    let step = rate / SAMPLE_RATE as f64;
+    let length = (frames.len() as f64 / step) as usize;
+    (0..length)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position as usize;
+            let t = (position - index as f64) as f32;
+            let a = frames[index];
+            let b = frames.get(index + 1).copied().unwrap_or(a);
+            [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]
+        })
+        .collect()
+}
+
+fn synthesize(source: impl Decodable<DecoderItem = f32>) -> Vec<[f32; 2]> {
+    let frames: Vec<[f32; 2]> = source.decoder().map(|sample| [sample, sample]).collect();
+    resample(&frames, synth::SAMPLE_RATE as f64)
+}
+
+/// One note in the mix.
+struct MixVoice<'a> {
+    start: usize,
+    /// Where the note stops, which is earlier than the end of its frames if it was cut off.
+    end: usize,
+    frames: Cow<'a, [[f32; 2]]>,
+    gains: [f32; 2],
+}
+
+/// Mixes the notes triggered on each simulation tick into `seconds` of stereo audio, tuned by
+/// `tuning`, with each hit's volume set by `dynamics` and panned by `spatial_audio` as heard from
+/// the middle of the board. Hits are dropped and cut off by the same rules as in the editor, then
+/// the result goes through a peak limiter, or is scaled down if the limiter is off, so it never
+/// clips.
+pub fn mix(
+    hits: &[(u64, NoteEvent)],
+    seconds: f32,
+    bank: &SampleBank,
+    tuning: &Tuning,
+    dynamics: &Dynamics,
+    spatial_audio: &SpatialAudio,
+    voice_settings: &VoiceSettings,
+) -> Vec<[f32; 2]> {
+    let mut voices: Vec<MixVoice> = Vec::new();
+    // Indices of the voices that haven't been cut off, oldest first.
+    let mut sounding = VecDeque::new();
+    let mut retrigger = Retrigger::default();
+    for (tick, note_event) in hits {
+        let time = *tick as f32 * TIMESTEP;
+        if note_event.hit && !retrigger.allow(note_event.peg, time, voice_settings.cooldown) {
+            continue;
+        }
+        let start = (*tick as f64 * TIMESTEP as f64 * SAMPLE_RATE as f64).round() as usize;
+        let touch = dynamics.touch(note_event.speed);
+        let gains = spatial_audio.gains(note_event.position).map(|gain| gain * touch.volume);
+        for &note in &note_event.notes {
+            let frames = match &note_event.voice {
+                Voice::Samples => {
+                    let Some(Sample(frames)) = bank.samples.get(&(note_event.instrument.clone(), note)) else { continue };
+                    Cow::Borrowed(frames.as_slice())
+                }
+                Voice::Synth(patch) => {
+                    let Some(frequency) = tuning.frequency(note) else { continue };
+                    Cow::Owned(synthesize(SynthNote::struck(patch, frequency, touch)))
+                }
+                Voice::Drum(Drum::Custom(path)) => {
+                    let Some(Sample(frames)) = bank.drums.get(path) else { continue };
+                    Cow::Borrowed(frames.as_slice())
+                }
+                Voice::Drum(drum) => Cow::Owned(synthesize(DrumHit(drum.clone()))),
+            };
+            sounding.retain(|&i: &usize| voices[i].end > start);
+            while sounding.len() >= voice_settings.max_voices.max(1) {
+                let Some(oldest) = sounding.pop_front() else { break };
+                voices[oldest].end = start;
+            }
+            sounding.push_back(voices.len());
+            voices.push(MixVoice { start, end: start + frames.len(), frames, gains });
+        }
+    }
+
+    let mut output = vec![[0.; 2]; (seconds * SAMPLE_RATE as f32) as usize];
+    for voice in &voices {
+        let [left, right] = voice.gains;
+        for (out, frame) in output.iter_mut().skip(voice.start).zip(&voice.frames[..voice.end - voice.start]) {
+            out[0] += frame[0] * left;
+            out[1] += frame[1] * right;
+        }
+    }
+    if voice_settings.limiter {
+        limit(&mut output);
+    } else {
+        let peak = output.iter().flatten().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        if peak > 1. {
+            for sample in output.iter_mut().flatten() {
+                *sample /= peak;
+            }
+        }
+    }
+    output
+}
+
+/// Turns the level down instantly wherever it would clip, letting it back up at the same rate
+/// as the editor's limiter.
+fn limit(frames: &mut [[f32; 2]]) {
+    let release = voices::RELEASE / SAMPLE_RATE as f32;
+    let mut gain = 1f32;
+    for frame in frames {
+        let peak = frame[0].abs().max(frame[1].abs());
+        gain = (gain + release).min(1.);
+        if peak * gain > 1. {
+            gain = 1. / peak;
+        }
+        frame[0] *= gain;
+        frame[1] *= gain;
+    }
+}
+
+pub fn write_wav(path: &str, frames: &[[f32; 2]]) -> Result<(), String> {
+    let error = |e: hound::Error| format!("could not write {}: {}", path, e);
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).map_err(error)?;
+    for sample in frames.iter().flatten() {
+        writer.write_sample((sample * i16::MAX as f32) as i16).map_err(error)?;
+    }
+    writer.finalize().map_err(error)
+}
@@ -1,189 +1,191 @@
 use crate::camera::{Background, MainCamera};
+use crate::bars::{self, Bar};
+use crate::chords::{ChordEditor, ChordEditorUi};
+use crate::drums::Drum;
+use crate::history::{ApplyEdit, Edit, History};
+use crate::instruments::{InstrumentId, Instruments};
+use crate::scales::{key_picker, Key, Scale};
+use crate::sequences::PlayMode;
+use crate::spawners::SpawnPattern;
+use crate::simulation::{Ball, BallSpawner, DeleteObjects, NoteEvent, NotesList, Object, ObjectId, Peg, Percussion, Pitch, SceneObjects, SpawnObject};
+use crate::synth::Voice;
 use crate::ui::ui;
-use bevy::{prelude::*, window::PrimaryWindow};
-use bevy_rapier2d::prelude::*;
-use bevy_egui::EguiContexts;
-use std::collections::BTreeMap;
-use serde::{Serialize, Deserialize};
+use crate::walls::{self, Wall};
+use bevy::{ecs::system::SystemParam, prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui, EguiContexts};
 
+/// Editing and drawing for the objects simulated by `SimulationPlugin`.
 pub struct PegPlugin;
 
 impl Plugin for PegPlugin {
     fn build(&self, app: &mut App) {
         app
-            .insert_resource(SceneObjects { objects: BTreeMap::new(), object_count: 0 })
             .insert_resource(Octave(3))
+            .init_resource::<NewPegSettings>()
             .insert_resource(CurrentDraggedPegId(None))
-            .insert_resource(ChordInput { input_active: false, input_notes: Vec::new() })
-            .add_event::<SpawnObject>()
-            .add_event::<DeleteObjects>()
-            .add_systems(FixedUpdate, delete_all_objects)
-            .add_systems(FixedUpdate, spawn_object)
+            .insert_resource(SelectedObject(None))
+            .init_resource::<ChordEditor>()
+            .add_systems(Update, add_object_sprites)
             .add_systems(FixedUpdate, spawn_ball.after(ui))
             .add_systems(FixedUpdate, spawn_ball_spawner.after(ui))
-            .add_systems(FixedUpdate, cleanup_sounds)
-            .add_systems(Startup, setup_sound)
             .add_systems(FixedUpdate, place_peg)
-            .add_systems(FixedUpdate, display_events)
+            .add_systems(FixedUpdate, place_drum)
+            .add_systems(FixedUpdate, flash_background)
             .add_systems(FixedUpdate, drag_peg)
             .add_systems(FixedUpdate, clear_screen);
     
     }
 }
 
+/// The octave the note keys play in, where middle C is in octave 4.
 #[derive(Resource)]
 pub struct Octave(pub u32);
 
-#[derive(Resource, Clone, Serialize, Deserialize)]
-pub struct SceneObjects {
-    pub objects: BTreeMap<u32, Object>,
-    object_count: u32
+/// The highest octave whose C is a MIDI note (C9 is 120).
+const MAX_OCTAVE: u32 = 9;
+
+/// How pegs placed from the keyboard or a MIDI keyboard are set up.
+#[derive(Resource, Default)]
+pub struct NewPegSettings {
+    pub instrument: InstrumentId,
+    /// What K places.
+    pub drum: Drum,
+    /// The key the letter keys play in.
+    pub key: Key,
+    /// The key "Re-key scene" moves the pegs into.
+    rekey_to: Key,
 }
 
-#[derive(Resource, Clone, Serialize, Deserialize)]
-pub enum Object {
-    Peg(f32, f32, Vec<u32>),
-    Ball(f32, f32),
-    BallSpawner(f32, f32),
+impl NewPegSettings {
+    pub fn peg(&self, position: Vec2, notes: Vec<Pitch>) -> Object {
+        Object::Peg {
+            x: position.x,
+            y: position.y,
+            notes,
+            voice: Voice::default(),
+            instrument: self.instrument.clone(),
+            mode: PlayMode::default(),
+        }
+    }
+
+    /// A bar from `start` to `end`, with the first note at whichever end is further left.
+    pub fn bar(&self, start: Vec2, end: Vec2, notes: Vec<Pitch>) -> Object {
+        let (left, right) = if start.x <= end.x { (start, end) } else { (end, start) };
+        let center = (left + right) / 2.;
+        Object::Bar {
+            x: center.x,
+            y: center.y,
+            length: left.distance(right),
+            angle: (right.y - left.y).atan2(right.x - left.x).to_degrees(),
+            notes,
+            voice: Voice::default(),
+            instrument: self.instrument.clone(),
+            mode: PlayMode::default(),
+            split: false,
+        }
+    }
+
+    pub fn drum(&self, position: Vec2) -> Object {
+        Object::Drum { x: position.x, y: position.y, sound: self.drum.clone() }
+    }
 }
 
-#[derive(Event)]
-pub struct SpawnObject(pub Object, pub Option<u32>);
-
-#[derive(Component)]
-pub struct Peg;
-
-#[derive(Component)]
-pub struct Ball;
-
-#[derive(Component, Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Copy)]
-enum Notes {
-    C3,
-    CS3,
-    D3,
-    DS3,
-    E3,
-    F3,
-    FS3,
-    G3,
-    GS3,
-    A3,
-    AS3,
-    B3,
-    C4,
-    CS4,
-    D4,
-    DS4,
-    E4,
-    F4,
-    FS4,
-    G4,
-    GS4,
-    A4,
-    AS4,
-    B4,
-    C5,
+/// The side panel's settings for new pegs.
+#[derive(SystemParam)]
+pub struct NewPegUi<'w> {
+    settings: ResMut<'w, NewPegSettings>,
+    instruments: Res<'w, Instruments>,
+    scene_objects: Res<'w, SceneObjects>,
+    apply_edit_writer: EventWriter<'w, ApplyEdit>,
+    chord_editor: ChordEditorUi<'w>,
 }
 
-impl Notes {
-    fn to_file_path(&self) -> String {
-        match self {
-            Notes::C3 => "sounds/c3.ogg",
-            Notes::CS3 => "sounds/cs3.ogg",
-            Notes::D3 => "sounds/d3.ogg",
-            Notes::DS3 => "sounds/ds3.ogg",
-            Notes::E3 => "sounds/e3.ogg",
-            Notes::F3 => "sounds/f3.ogg",
-            Notes::FS3 => "sounds/fs3.ogg",
-            Notes::G3 => "sounds/g3.ogg",
-            Notes::GS3 => "sounds/gs3.ogg",
-            Notes::A3 => "sounds/a3.ogg",
-            Notes::AS3 => "sounds/as3.ogg",
-            Notes::B3 => "sounds/b3.ogg",
-            Notes::C4 => "sounds/c4.ogg",
-            Notes::CS4 => "sounds/cs4.ogg",
-            Notes::D4 => "sounds/d4.ogg",
-            Notes::DS4 => "sounds/ds4.ogg",
-            Notes::E4 => "sounds/e4.ogg",
-            Notes::F4 => "sounds/f4.ogg",
-            Notes::FS4 => "sounds/fs4.ogg",
-            Notes::G4 => "sounds/g4.ogg",
-            Notes::GS4 => "sounds/gs4.ogg",
-            Notes::A4 => "sounds/a4.ogg",
-            Notes::AS4 => "sounds/as4.ogg",
-            Notes::B4 => "sounds/b4.ogg",
-            Notes::C5 => "sounds/c5.ogg",
+impl NewPegUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        instrument_picker(ui, "Instrument", &self.instruments, &mut self.settings.instrument);
+        drum_picker(ui, "Drum (K)", &mut self.settings.drum);
+        key_picker(ui, "Key", &mut self.settings.key);
+        ui.separator();
+        key_picker(ui, "New key", &mut self.settings.rekey_to);
+        let button = ui.button("Re-key scene");
+        let hover = format!("Move every peg from {} into {}", self.settings.key.name(), self.settings.rekey_to.name());
+        if button.on_hover_text(hover).clicked() {
+            self.rekey_scene();
+        }
+        ui.collapsing("Chord", |ui| self.chord_editor.show(ui, &self.settings.instrument));
+    }
+
+    fn rekey_scene(&mut self) {
+        let NewPegSettings { key, rekey_to, .. } = &mut *self.settings;
+        let edits: Vec<Edit> = self
+            .scene_objects
+            .objects
+            .iter()
+            .filter_map(|(&id, object)| {
+                let mut after = object.clone();
+                let (notes, ..) = after.sound_mut()?;
+                let new_notes: Vec<Pitch> = notes.iter().map(|&note| key.rekey(note, rekey_to)).collect();
+                if new_notes == *notes {
+                    return None;
+                }
+                *notes = new_notes;
+                Some(Edit::Modify { id, before: object.clone(), after })
+            })
+            .collect();
+        if !edits.is_empty() {
+            self.apply_edit_writer.send(ApplyEdit(Edit::Group { name: "Re-key scene", edits }));
         }
-        .into()
+        *key = rekey_to.clone();
     }
 }
-fn setup_sound(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn(AudioBundle {
-        source: asset_server.load("sounds/c3.ogg"),
-        ..default()
-    });
+
+pub fn instrument_picker(ui: &mut egui::Ui, label: &str, instruments: &Instruments, instrument: &mut InstrumentId) {
+    egui::ComboBox::from_label(label)
+        .selected_text(instruments.name(instrument))
+        .show_ui(ui, |ui| {
+            for (id, candidate) in instruments.iter() {
+                ui.selectable_value(instrument, id.clone(), &candidate.name);
+            }
+        });
 }
 
-fn cleanup_sounds(mut commands: Commands, controller: Query<(Entity, &AudioSink)>) {
-    for (id, sink) in controller.iter() {
-        if sink.empty() {
-            commands.entity(id).despawn();
-        }
+/// Picks a built-in drum, or a custom sample by its path in the assets folder.
+pub fn drum_picker(ui: &mut egui::Ui, label: &str, drum: &mut Drum) {
+    egui::ComboBox::from_label(label)
+        .selected_text(drum.name())
+        .show_ui(ui, |ui| {
+            for candidate in Drum::BUILT_IN {
+                let name = candidate.name();
+                ui.selectable_value(drum, candidate, name);
+            }
+            if ui.selectable_label(matches!(drum, Drum::Custom(_)), Drum::Custom(String::new()).name()).clicked()
+                && !matches!(drum, Drum::Custom(_))
+            {
+                *drum = Drum::Custom(String::new());
+            }
+        });
+    if let Drum::Custom(path) = drum {
+        ui.horizontal(|ui| {
+            ui.label("Sample");
+            ui.text_edit_singleline(path).on_hover_text("An ogg file in the assets folder, e.g. sounds/cowbell.ogg");
+        });
     }
 }
 
-fn display_events(
-    mut collision_events: EventReader<CollisionEvent>,
-    mut commands: Commands,
-    peg_query: Query<(&NotesList, &Sprite)>,
-    mut background_query: Query<&mut Sprite, (With<Background>, Without<NotesList>)>,
-    asset_server: Res<AssetServer>,
+fn flash_background(
+    mut note_events: EventReader<NoteEvent>,
+    peg_query: Query<&Sprite, With<Peg>>,
+    mut background_query: Query<&mut Sprite, (With<Background>, Without<Peg>)>,
 ) {
     let Ok(mut background_sprite) = background_query.get_single_mut() else { return };
-    for collision_event in collision_events.read() {
-        match collision_event {
-            CollisionEvent::Started(e1, e2, _flags) => {
-                let notes;
-                let peg_sprite;
-                match peg_query.get(*e1) {
-                    Ok((n, sprite)) => {
-                        notes = n;
-                        peg_sprite = sprite;
-                    }
-                    Err(_) => match peg_query.get(*e2) {
-                        Ok((n, sprite)) => {
-                            notes = n;
-                            peg_sprite = sprite;
-                        }
-                        Err(_) => return,
-                    },
-                }
-                for note in &notes.0 {
-                    commands.spawn(AudioBundle {
-                        source: asset_server.load(note.to_file_path()),
-                        ..default()
-                    });
-                }
-                
-                // use peg_sprite
-                background_sprite.color = Color::rgb_from_array(peg_sprite.color.rgb_to_vec3() / 3.5);
-                // peg_sprite.color = Color::rgb_from_array((-1. * peg_sprite.color.rgb_to_vec3()).exp());
-            }
-            _ => {}
-        }
+    for note_event in note_events.read() {
+        let Ok(peg_sprite) = peg_query.get(note_event.peg) else { continue };
+        background_sprite.color = Color::rgb_from_array(peg_sprite.color.rgb_to_vec3() / 3.5);
+        // peg_sprite.color = Color::rgb_from_array((-1. * peg_sprite.color.rgb_to_vec3()).exp());
     }
     background_sprite.color = Color::rgb_from_array(background_sprite.color.rgb_to_vec3() / 1.1);
 }
 
-#[derive(Component)]
-pub struct NotesList(Vec<Notes>);
-
-#[derive(Component)]
-pub struct BallSpawner;
-
-#[derive(Component)]
-struct ObjectId(u32);
-
 fn gaussian_sample(x: f32, mean: f32) -> f32 {
     4. * (-8. * ((x - mean) / 2.).powf(2.)).exp()
 }
@@ -192,95 +194,56 @@ fn gaussian_sample_triple(mean: f32) -> (f32, f32, f32) {
     (gaussian_sample(0., mean), gaussian_sample(1., mean), gaussian_sample(2., mean))
 }
 
-fn spawn_object(
+/// C3 to C5 sweep from red to green; the rest of the range is clamped short of black.
+fn note_color(note: Pitch) -> Color {
+    let (r, g, b) = gaussian_sample_triple(((note.0 as f32 - 48.) / 24.).clamp(-0.5, 2.));
+    Color::rgb(r, g, b)
+}
+
+fn object_sprite(asset_server: &AssetServer, image: &'static str, color: Color) -> impl Bundle {
+    (
+        asset_server.load::<Image>(image),
+        Sprite {
+            color,
+            custom_size: Some(Vec2::splat(100.)),
+            ..default()
+        },
+        VisibilityBundle::default(),
+    )
+}
+
+fn add_object_sprites(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    mut scene_objects: ResMut<SceneObjects>,
-    mut spawn_events: EventReader<SpawnObject>,
+    pegs: Query<(Entity, &NotesList), (Added<Peg>, Without<Percussion>, Without<Bar>)>,
+    bars: Query<(Entity, &NotesList, &Bar), Added<Bar>>,
+    drums: Query<(Entity, &Voice), Added<Percussion>>,
+    balls: Query<Entity, Added<Ball>>,
+    ball_spawners: Query<Entity, Added<BallSpawner>>,
 ) {
-    for ev in spawn_events.read() {
-        match ev.0 {
-            Object::Peg(x, y, ref notes) => {
-                let noteslist = NotesList(notes.iter().map(|&i| convert_index_to_note(i)).collect());
-                let (r, g, b) = gaussian_sample_triple(notes[0] as f32 / 24.);
-                commands
-                    .spawn(SpriteBundle {
-                        texture: asset_server.load("peg.png"),
-                        sprite: Sprite {
-                            color: Color::rgb(r, g, b),
-                            custom_size: Some(Vec2::splat(100.)),
-                            ..default()
-                        },
-                        transform: Transform {
-                            translation: Vec3::new(x, y, 1.),
-                            scale: Vec3::new(0.3, 0.3, 1.),
-                            ..default()
-                        },
-                        ..default()
-                    })
-                    .insert(Peg)
-                    .insert(ObjectId(ev.1.unwrap_or(scene_objects.object_count)))
-                    .insert(RigidBody::Fixed)
-                    .insert(Collider::ball(45.))
-                    .insert(noteslist)
-                    .insert(Restitution {
-                        coefficient: 0.7,
-                        combine_rule: CoefficientCombineRule::Max,
-                    });
-                if ev.1.is_none() {
-                    let object_count = scene_objects.object_count;
-                    scene_objects.objects.insert(object_count, ev.0.clone());
-                    scene_objects.object_count += 1;
-                }
-            }
-            Object::Ball(x, y) => {
-                commands
-                    .spawn(SpriteBundle {
-                        texture: asset_server.load("peg.png"),
-                        sprite: Sprite {
-                            color: Color::rgb(7.5, 0.0, 7.5),
-                            // color: Color::WHITE,
-                            custom_size: Some(Vec2::splat(100.)),
-                            ..default()
-                        },
-                        transform: Transform {
-                            translation: Vec3::new(x, y, 1.),
-                            scale: Vec3::new(0.3, 0.3, 1.),
-                            ..default()
-                        },
-                        ..default()
-                    })
-                    .insert(Ball)
-                    .insert(GravityScale(2.0))
-                    .insert(RigidBody::Dynamic)
-                    .insert(ActiveEvents::COLLISION_EVENTS)
-                    .insert(Collider::ball(45.));
-            }
-            Object::BallSpawner(x, y) => {
-                commands
-                    .spawn(SpriteBundle {
-                        texture: asset_server.load("peg.png"),
-                        sprite: Sprite {
-                            color: Color::rgb(0.0, 0.0, 10.0),
-                            custom_size: Some(Vec2::splat(100.)),
-                            ..default()
-                        },
-                        transform: Transform {
-                            translation: Vec3::new(x, y, 1.),
-                            scale: Vec3::new(0.3, 0.3, 1.),
-                            ..default()
-                        },
-                        ..default()
-                    })
-                    .insert(BallSpawner)
-                    .insert(ObjectId(ev.1.unwrap_or(scene_objects.object_count)));
-                if ev.1.is_none() {
-                    let object_count = scene_objects.object_count;
-                    scene_objects.objects.insert(object_count, ev.0.clone());
-                    scene_objects.object_count += 1;
-                }
-            }
-        }
+    for (e, notes) in pegs.iter() {
+        commands.entity(e).insert(object_sprite(&asset_server, "peg.png", note_color(notes.0[0])));
+    }
+    for (e, notes, bar) in bars.iter() {
+        commands.entity(e).insert((
+            asset_server.load::<Image>("white.png"),
+            Sprite {
+                color: note_color(notes.0[0]),
+                custom_size: Some(Vec2::new(bar.length, bars::THICKNESS)),
+                ..default()
+            },
+            VisibilityBundle::default(),
+        ));
+    }
+    for (e, voice) in drums.iter() {
+        let Voice::Drum(drum) = voice else { continue };
+        commands.entity(e).insert(object_sprite(&asset_server, "peg2.png", drum.color()));
+    }
+    for e in balls.iter() {
+        commands.entity(e).insert(object_sprite(&asset_server, "peg.png", Color::rgb(7.5, 0.0, 7.5)));
+    }
+    for e in ball_spawners.iter() {
+        commands.entity(e).insert(object_sprite(&asset_server, "peg.png", Color::rgb(0.0, 0.0, 10.0)));
     }
 }
 
@@ -299,7 +262,7 @@ fn spawn_ball(
             .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
             .map(|ray| ray.origin.truncate())
         {
-            spawn_event_writer.send(SpawnObject(Object::Ball(position.x, position.y), None));
+            spawn_event_writer.send(SpawnObject(Object::Ball { x: position.x, y: position.y, spawner: None, velocity: [0.; 2] }, None));
         }
     }
 }
@@ -319,42 +282,81 @@ fn spawn_ball_spawner(
             .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
             .map(|ray| ray.origin.truncate())
         {
-            spawn_event_writer.send(SpawnObject(Object::BallSpawner(position.x, position.y), None));
+            spawn_event_writer.send(SpawnObject(Object::BallSpawner { x: position.x, y: position.y, pattern: SpawnPattern::default() }, None));
         }
     }
 }
 
-#[derive(Event)]
-pub struct DeleteObjects;
-
-pub fn delete_all_objects(
-    mut delete_events: EventReader<DeleteObjects>,
-    query_all_objects: Query<Entity, Or<(With<Peg>, With<Ball>, With<BallSpawner>)>>,
-    mut scene_objects: ResMut<SceneObjects>,
-    mut commands: Commands,
+fn clear_screen(
+    input: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    scene_objects: Res<SceneObjects>,
+    mut history: ResMut<History>,
+    mut delete_event_writer: EventWriter<DeleteObjects>,
 ) {
-    for _ in delete_events.read() {
-        for e in query_all_objects.iter() {
-            commands.entity(e).despawn();
+    if input.just_pressed(KeyCode::KeyR) && !contexts.ctx_mut().wants_keyboard_input() {
+        if !scene_objects.objects.is_empty() {
+            history.record(Edit::Clear {
+                objects: scene_objects.objects.clone(),
+                object_count: scene_objects.object_count,
+            });
         }
-        scene_objects.objects.clear();
-        scene_objects.object_count = 0;
+        delete_event_writer.send(DeleteObjects);
     }
 }
 
-fn clear_screen(
+fn place_drum(
     input: Res<ButtonInput<KeyCode>>,
-    mut delete_event_writer: EventWriter<DeleteObjects>,
+    mut contexts: EguiContexts,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    new_peg_settings: Res<NewPegSettings>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
 ) {
-    if input.just_pressed(KeyCode::KeyR) {
-        delete_event_writer.send(DeleteObjects);
+    let (camera, camera_transform) = primary_camera.single();
+    if input.just_pressed(KeyCode::KeyK) && !contexts.ctx_mut().wants_keyboard_input() {
+        if let Some(position) = primary_window
+            .single()
+            .cursor_position()
+            .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+            .map(|ray| ray.origin.truncate())
+        {
+            spawn_event_writer.send(SpawnObject(new_peg_settings.drum(position), None));
+        }
     }
 }
 
-#[derive(Resource)]
-struct ChordInput {
-    input_active: bool,
-    input_notes: Vec<u32>,
+/// Letter keys in scale order, so that with a scale selected C plays the tonic, D the second
+/// degree and so on.
+const NOTE_KEYS: [(KeyCode, u32); 7] = [
+    (KeyCode::KeyC, 0),
+    (KeyCode::KeyD, 2),
+    (KeyCode::KeyE, 4),
+    (KeyCode::KeyF, 5),
+    (KeyCode::KeyG, 7),
+    (KeyCode::KeyA, 9),
+    (KeyCode::KeyB, 11),
+];
+
+/// The note for a letter key pressed this frame, if any. Without a scale, the letters play the
+/// notes they're named after and Shift and Ctrl make them sharp or flat; with one, they play
+/// scale degrees and Shift and Ctrl move up or down a degree.
+fn pressed_note(input: &ButtonInput<KeyCode>, octave: u32, key: &Key) -> Option<Pitch> {
+    let degree = NOTE_KEYS.iter().position(|&(key_code, _)| input.just_pressed(key_code))?;
+    let sharp = input.pressed(KeyCode::ShiftLeft) || input.just_pressed(KeyCode::ShiftRight);
+    let flat = input.pressed(KeyCode::ControlLeft) || input.just_pressed(KeyCode::ControlRight);
+    if key.scale != Scale::Chromatic {
+        return Some(key.degree(octave, degree as i32 + sharp as i32 - flat as i32));
+    }
+    // MIDI note number of C in the current octave
+    let mut index = (octave + 1) * 12 + NOTE_KEYS[degree].1;
+    if sharp && index < Pitch::MAX.0 as u32 {
+        index += 1;
+    }
+    if flat {
+        index = index.saturating_sub(1);
+    }
+    Some(Pitch(index.min(Pitch::MAX.0 as u32) as u8))
 }
 
 fn place_peg(
@@ -363,194 +365,56 @@ fn place_peg(
     primary_window: Query<&Window, With<PrimaryWindow>>,
     primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
     mut octave: ResMut<Octave>,
-    mut chord_input: ResMut<ChordInput>,
+    chord_editor: Res<ChordEditor>,
+    new_peg_settings: Res<NewPegSettings>,
+    mut contexts: EguiContexts,
 ) {
-    if chord_input.input_active {
-        if input.just_pressed(KeyCode::Enter) {
-            chord_input.input_active = false;
-            let (camera, camera_transform) = primary_camera.single();
-            if chord_input.input_notes.len() > 0 {
-                if let Some(position) = primary_window
-                    .single()
-                    .cursor_position()
-                    .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
-                    .map(|ray| ray.origin.truncate())
-                {
-                    spawn_event_writer.send(SpawnObject(Object::Peg(position.x, position.y, chord_input.input_notes.clone()), None));
-                }
-            }
-            chord_input.input_notes = Vec::new();
-            return;
-        }
-        if input.just_pressed(KeyCode::Digit1) {
-            octave.0 = 3;
-        }
-        if input.just_pressed(KeyCode::Digit2) {
-            octave.0 = 4;
-        }
-        let mut index = if octave.0 == 3 {
-            0
-        } else {
-            12
-        };
-        let mut shouldspawn = false;
-        
-        if input.just_pressed(KeyCode::KeyC) {
-            shouldspawn = true;
-            index += 0;
-        }
-        if input.just_pressed(KeyCode::KeyD) {
-            shouldspawn = true;
-
-            index += 2;
-        }
-        if input.just_pressed(KeyCode::KeyE) {
-            shouldspawn = true;
-            index += 4;
-        }
-        if input.just_pressed(KeyCode::KeyF) {
-            shouldspawn = true;
-            index += 5;
-        }
-        if input.just_pressed(KeyCode::KeyG) {
-            shouldspawn = true;
-            index += 7;
-        }
-        if input.just_pressed(KeyCode::KeyA) {
-            shouldspawn = true;
-            index += 9;
-        } 
-        if input.just_pressed(KeyCode::KeyB) {
-            shouldspawn = true;
-            index += 11;
-        }
-        
-        
-        if input.pressed(KeyCode::ShiftLeft) || input.just_pressed(KeyCode::ShiftRight) {
-            if index != 24 {
-                index += 1;
-            }
-        }
-
-        if input.pressed(KeyCode::ControlLeft) || input.just_pressed(KeyCode::ControlRight) {
-            if index != 0 {
-                index -= 1;
-            }
-        }
-        if shouldspawn {
-            chord_input.input_notes.push(index);
-        }
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+    // 1 and 2 jump to octaves 3 and 4; - and = step through the rest of the MIDI range.
+    if input.just_pressed(KeyCode::Minus) {
+        octave.0 = octave.0.saturating_sub(1);
+    }
+    if input.just_pressed(KeyCode::Equal) {
+        octave.0 = (octave.0 + 1).min(MAX_OCTAVE);
+    }
+    if input.just_pressed(KeyCode::Digit1) {
+        octave.0 = 3;
+    }
+    if input.just_pressed(KeyCode::Digit2) {
+        octave.0 = 4;
+    }
+    // Enter places the chord editor's chord, unless the cursor is over the side panel.
+    let notes = if input.just_pressed(KeyCode::Enter) && !contexts.ctx_mut().is_pointer_over_area() {
+        chord_editor.notes.clone()
     } else {
-        if input.just_pressed(KeyCode::Enter) {
-            chord_input.input_active = true;
-        } else {
-            if input.just_pressed(KeyCode::Digit1) {
-                octave.0 = 3;
-            }
-            if input.just_pressed(KeyCode::Digit2) {
-                octave.0 = 4;
-            }
-            let mut index = if octave.0 == 3 {
-                0
-            } else {
-                12
-            };
-            let mut shouldspawn = false;
-            
-            if input.just_pressed(KeyCode::KeyC) {
-                shouldspawn = true;
-                index += 0;
-            }
-            if input.just_pressed(KeyCode::KeyD) {
-                shouldspawn = true;
-
-                index += 2;
-            }
-            if input.just_pressed(KeyCode::KeyE) {
-                shouldspawn = true;
-                index += 4;
-            }
-            if input.just_pressed(KeyCode::KeyF) {
-                shouldspawn = true;
-                index += 5;
-            }
-            if input.just_pressed(KeyCode::KeyG) {
-                shouldspawn = true;
-                index += 7;
-            }
-            if input.just_pressed(KeyCode::KeyA) {
-                shouldspawn = true;
-                index += 9;
-            } 
-            if input.just_pressed(KeyCode::KeyB) {
-                shouldspawn = true;
-                index += 11;
-            }
-            
-            
-            if input.pressed(KeyCode::ShiftLeft) || input.just_pressed(KeyCode::ShiftRight) {
-                if index != 24 {
-                    index += 1;
-                }
-            }
-
-            if input.pressed(KeyCode::ControlLeft) || input.just_pressed(KeyCode::ControlRight) {
-                if index != 0 {
-                    index -= 1;
-                }
-            }
-            if shouldspawn {
-                // chord_input.input_notes.push(index);
-                let (camera, camera_transform) = primary_camera.single();
-                if let Some(position) = primary_window
-                    .single()
-                    .cursor_position()
-                    .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
-                    .map(|ray| ray.origin.truncate())
-                {
-                    spawn_event_writer.send(SpawnObject(Object::Peg(position.x, position.y, vec![index]), None));
-                }
-            }
-        }
+        pressed_note(&input, octave.0, &new_peg_settings.key).into_iter().collect()
+    };
+    if notes.is_empty() {
+        return;
     }
-    
-}
-
-fn convert_index_to_note(i: u32) -> Notes {
-    match i {
-        0 => Notes::C3,
-        1 => Notes::CS3,
-        2 => Notes::D3,
-        3 => Notes::DS3,
-        4 => Notes::E3,
-        5 => Notes::F3,
-        6 => Notes::FS3,
-        7 => Notes::G3,
-        8 => Notes::GS3,
-        9 => Notes::A3,
-        10 => Notes::AS3,
-        11 => Notes::B3,
-        12 => Notes::C4,
-        13 => Notes::CS4,
-        14 => Notes::D4,
-        15 => Notes::DS4,
-        16 => Notes::E4,
-        17 => Notes::F4,
-        18 => Notes::FS4,
-        19 => Notes::G4,
-        20 => Notes::GS4,
-        21 => Notes::A4,
-        22 => Notes::AS4,
-        23 => Notes::B4,
-        24 => Notes::C5,
-        _ => Notes::C3,
+    let (camera, camera_transform) = primary_camera.single();
+    if let Some(position) = primary_window
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+    {
+        spawn_event_writer.send(SpawnObject(new_peg_settings.peg(position, notes), None));
     }
 }
 
+/// The object being dragged, as it was when the drag started, and where it was grabbed.
 #[derive(Resource)]
-struct CurrentDraggedPegId(Option<u32>);
+pub struct CurrentDraggedPegId(Option<(u32, Object, Vec2)>);
 
-fn drag_peg(
+/// The object shown in the side panel's inspector.
+#[derive(Resource)]
+pub struct SelectedObject(pub Option<u32>);
+
+/// Selects, drags and deletes (X) the object under the cursor.
+pub fn drag_peg(
     input: Res<ButtonInput<MouseButton>>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
     mut contexts: EguiContexts,
@@ -558,8 +422,11 @@ fn drag_peg(
     primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
     mut pegs: Query<(&mut Transform, &ObjectId, Entity), (With<Peg>, Without<BallSpawner>)>,
     mut ball_spawners: Query<(&mut Transform, &ObjectId, Entity), With<BallSpawner>>,
+    mut walls: Query<(&mut Transform, &ObjectId, Entity), (With<Wall>, Without<Peg>, Without<BallSpawner>)>,
     mut scene_objects: ResMut<SceneObjects>,
     mut current_dragged_peg_id: ResMut<CurrentDraggedPegId>,
+    mut selected_object: ResMut<SelectedObject>,
+    mut history: ResMut<History>,
     mut commands: Commands,
 ) {
     let peg_radius = 18.;
@@ -572,17 +439,21 @@ fn drag_peg(
             .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
             .map(|ray| ray.origin.truncate())
         {
-            for (transform, ObjectId(id), entity_id) in pegs.iter() {
-                if transform.translation.truncate().distance(position) <= peg_radius {
+            for (transform, ObjectId(id), entity_id) in pegs.iter().chain(ball_spawners.iter()) {
+                if transform.translation.truncate().distance(position) <= peg_radius || bar_under(&scene_objects, *id, position) {
                     commands.entity(entity_id).despawn();
-                    scene_objects.objects.remove(id);
+                    if let Some(object) = scene_objects.objects.remove(id) {
+                        history.record(Edit::Delete { id: *id, object });
+                    }
                     return;
                 }
             }
-            for (transform, ObjectId(id), entity_id) in ball_spawners.iter() {
-                if transform.translation.truncate().distance(position) <= peg_radius {
+            for (_, ObjectId(id), entity_id) in walls.iter() {
+                if wall_under(&scene_objects, *id, position) {
                     commands.entity(entity_id).despawn();
-                    scene_objects.objects.remove(id);
+                    if let Some(object) = scene_objects.objects.remove(id) {
+                        history.record(Edit::Delete { id: *id, object });
+                    }
                     return;
                 }
             }
@@ -594,53 +465,80 @@ fn drag_peg(
             .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
             .map(|ray| ray.origin.truncate())
         {
-            for (transform, ObjectId(id), _) in pegs.iter() {
-                if transform.translation.truncate().distance(position) <= peg_radius {
-                    current_dragged_peg_id.0 = Some(*id);
+            for (transform, ObjectId(id), _) in pegs.iter().chain(ball_spawners.iter()) {
+                if transform.translation.truncate().distance(position) <= peg_radius || bar_under(&scene_objects, *id, position) {
+                    selected_object.0 = Some(*id);
+                    current_dragged_peg_id.0 = scene_objects.objects.get(id).map(|object| (*id, object.clone(), position));
                     return;
                 }
             }
-            for (transform, ObjectId(id), _) in ball_spawners.iter() {
-                if transform.translation.truncate().distance(position) <= peg_radius {
-                    current_dragged_peg_id.0 = Some(*id);
+            for (_, ObjectId(id), _) in walls.iter() {
+                if wall_under(&scene_objects, *id, position) {
+                    selected_object.0 = Some(*id);
+                    current_dragged_peg_id.0 = scene_objects.objects.get(id).map(|object| (*id, object.clone(), position));
                     return;
                 }
             }
+            selected_object.0 = None;
         }
     } else if input.pressed(MouseButton::Left) {
         match current_dragged_peg_id.0 {
             None => {},
-            Some(ref id) => {
+            Some((id, ref before, grabbed_at)) => {
                 if let Some(position) = primary_window
                     .single()
                     .cursor_position()
                     .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
                     .map(|ray| ray.origin.truncate())
                 {
-                    let object = scene_objects.objects.get_mut(id).unwrap();
+                    // The object may have been removed by an undo mid-drag.
+                    let Some(object) = scene_objects.objects.get_mut(&id) else {
+                        current_dragged_peg_id.0 = None;
+                        return;
+                    };
+                    // Bars and walls can be grabbed anywhere along their length, so they move by
+                    // however far the cursor has rather than jumping to it.
+                    let position = match before {
+                        Object::Bar { .. } => before.position() + position - grabbed_at,
+                        _ => position,
+                    };
                     let x;
                     let y;
                     match object {
-                        Object::Peg(ref mut x_, ref mut y_, _) => {
+                        Object::Peg { x: ref mut x_, y: ref mut y_, .. }
+                        | Object::Drum { x: ref mut x_, y: ref mut y_, .. }
+                        | Object::Bar { x: ref mut x_, y: ref mut y_, .. } => {
                             x = x_;
                             y = y_;
                         }
-                        Object::BallSpawner(ref mut x_, ref mut y_) => {
+                        Object::BallSpawner { x: ref mut x_, y: ref mut y_, .. } => {
                             x = x_;
                             y = y_;
                         }
+                        Object::Wall { ref mut points, .. } => {
+                            let Object::Wall { points: ref start, .. } = before else { return };
+                            let offset = position - grabbed_at;
+                            *points = start.iter().map(|&point| (Vec2::from(point) + offset).into()).collect();
+                            for (mut transform, ObjectId(obj_id), _) in walls.iter_mut() {
+                                if *obj_id == id {
+                                    transform.translation.x = points[0][0];
+                                    transform.translation.y = points[0][1];
+                                }
+                            }
+                            return;
+                        }
                         _ => panic!("Expected to be dragging peg; was not dragging peg!"),
                     }
                     *x = position.x;
                     *y = position.y;
                     for (mut transform, ObjectId(obj_id), _) in pegs.iter_mut() {
-                        if obj_id == id {
+                        if *obj_id == id {
                             transform.translation.x = position.x;
                             transform.translation.y = position.y;
                         }
                     }
                     for (mut transform, ObjectId(obj_id), _) in ball_spawners.iter_mut() {
-                        if obj_id == id {
+                        if *obj_id == id {
                             transform.translation.x = position.x;
                             transform.translation.y = position.y;
                         }
@@ -648,7 +546,20 @@ fn drag_peg(
                 }
             }
         }
-    } else {
-        current_dragged_peg_id.0 = None;
+    } else if let Some((id, before, _)) = current_dragged_peg_id.0.take() {
+        if let Some(after) = scene_objects.objects.get(&id) {
+            if *after != before {
+                history.record(Edit::Modify { id, before, after: after.clone() });
+            }
+        }
     }
 }
+
+fn wall_under(scene_objects: &SceneObjects, id: u32, position: Vec2) -> bool {
+    matches!(scene_objects.objects.get(&id), Some(Object::Wall { points, .. }) if walls::distance(points, position) <= walls::GRAB_DISTANCE)
+}
+
+fn bar_under(scene_objects: &SceneObjects, id: u32, position: Vec2) -> bool {
+    let Some(&Object::Bar { x, y, length, angle, .. }) = scene_objects.objects.get(&id) else { return false };
+    walls::distance(&bars::ends(x, y, length, angle), position) <= bars::THICKNESS / 2.
+}
@@ -0,0 +1,216 @@
+use bevy_egui::egui;
+use crate::simulation::Pitch;
+
+const PITCH_CLASSES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scale {
+    /// No scale: the letter keys play the notes they're named after.
+    Chromatic,
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    /// The notes picked in `Key::custom`.
+    Custom,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 12] = [
+        Scale::Chromatic,
+        Scale::Major,
+        Scale::Minor,
+        Scale::Dorian,
+        Scale::Phrygian,
+        Scale::Lydian,
+        Scale::Mixolydian,
+        Scale::Locrian,
+        Scale::MajorPentatonic,
+        Scale::MinorPentatonic,
+        Scale::Blues,
+        Scale::Custom,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scale::Chromatic => "Chromatic (off)",
+            Scale::Major => "Major",
+            Scale::Minor => "Minor",
+            Scale::Dorian => "Dorian",
+            Scale::Phrygian => "Phrygian",
+            Scale::Lydian => "Lydian",
+            Scale::Mixolydian => "Mixolydian",
+            Scale::Locrian => "Locrian",
+            Scale::MajorPentatonic => "Major pentatonic",
+            Scale::MinorPentatonic => "Minor pentatonic",
+            Scale::Blues => "Blues",
+            Scale::Custom => "Custom",
+        }
+    }
+
+    /// Semitones above the tonic of each degree.
+    fn steps(self) -> &'static [u8] {
+        match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+            Scale::Custom => &[],
+        }
+    }
+}
+
+/// A tonic and a scale, which new pegs are kept to.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Key {
+    /// Pitch class of the tonic, where 0 is C.
+    pub tonic: u8,
+    pub scale: Scale,
+    /// Which semitones above the tonic a custom scale has. The tonic is always in it.
+    pub custom: [bool; 12],
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key {
+            tonic: 0,
+            scale: Scale::Chromatic,
+            custom: [true, false, true, false, true, true, false, true, false, true, false, true],
+        }
+    }
+}
+
+impl Key {
+    pub fn name(&self) -> String {
+        match self.scale {
+            Scale::Chromatic => Scale::Chromatic.name().into(),
+            scale => format!("{} {}", PITCH_CLASSES[self.tonic as usize], scale.name()),
+        }
+    }
+
+    fn steps(&self) -> Vec<u8> {
+        match self.scale {
+            Scale::Custom => (0..12).filter(|&step| step == 0 || self.custom[step as usize]).collect(),
+            scale => scale.steps().to_vec(),
+        }
+    }
+
+    /// The note `degree` steps up the scale (0 being the tonic) from the tonic in `octave`, where
+    /// middle C is in octave 4. Degrees past the end of the scale carry on into the next octave.
+    pub fn degree(&self, octave: u32, degree: i32) -> Pitch {
+        let steps = self.steps();
+        let length = steps.len() as i32;
+        let note = (octave as i32 + 1) * 12
+            + self.tonic as i32
+            + degree.div_euclid(length) * 12
+            + steps[degree.rem_euclid(length) as usize] as i32;
+        Pitch(note.clamp(0, Pitch::MAX.0 as i32) as u8)
+    }
+
+    /// The note in the key closest to `note`, taking the lower one on a tie.
+    pub fn snap(&self, note: i32) -> i32 {
+        let steps = self.steps();
+        let above_tonic = note - self.tonic as i32;
+        let octave = above_tonic.div_euclid(12);
+        let candidates = steps.iter().map(|&step| step as i32).chain([12]);
+        let nearest = candidates.min_by_key(|&step| ((above_tonic - octave * 12 - step).abs(), step)).unwrap_or(0);
+        self.tonic as i32 + octave * 12 + nearest
+    }
+
+    /// Moves `pitch` from this key into `to`. Between scales with the same number of notes, each
+    /// note keeps its degree, so a major melody comes out in minor; otherwise the notes are
+    /// transposed by the nearest distance between the tonics and kept to the new scale. Either way
+    /// notes move by less than an octave.
+    pub fn rekey(&self, pitch: Pitch, to: &Key) -> Pitch {
+        let (from_steps, to_steps) = (self.steps(), to.steps());
+        let note = self.snap(pitch.0 as i32);
+        let shift = (to.tonic as i32 - self.tonic as i32 + 6).rem_euclid(12) - 6;
+        let moved = if from_steps.len() == to_steps.len() {
+            let above_tonic = note - self.tonic as i32;
+            let degree = from_steps.iter().position(|&step| step as i32 == above_tonic.rem_euclid(12)).unwrap_or(0);
+            let moved = to.tonic as i32 + above_tonic.div_euclid(12) * 12 + to_steps[degree] as i32;
+            // Stay in the octave closest to where the note was.
+            [moved - 12, moved, moved + 12].into_iter().min_by_key(|moved| (moved - note - shift).abs()).unwrap_or(moved)
+        } else {
+            to.snap(note + shift)
+        };
+        Pitch(moved.clamp(0, Pitch::MAX.0 as i32) as u8)
+    }
+}
+
+pub fn key_picker(ui: &mut egui::Ui, label: &str, key: &mut Key) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_source((label, "tonic"))
+            .width(50.)
+            .selected_text(PITCH_CLASSES[key.tonic as usize])
+            .show_ui(ui, |ui| {
+                for (tonic, name) in PITCH_CLASSES.iter().enumerate() {
+                    ui.selectable_value(&mut key.tonic, tonic as u8, *name);
+                }
+            });
+        egui::ComboBox::from_label(label).selected_text(key.scale.name()).show_ui(ui, |ui| {
+            for scale in Scale::ALL {
+                ui.selectable_value(&mut key.scale, scale, scale.name());
+            }
+        });
+    });
+    if key.scale == Scale::Custom {
+        ui.horizontal_wrapped(|ui| {
+            for step in 1..12 {
+                let name = PITCH_CLASSES[(key.tonic as usize + step) % 12];
+                ui.toggle_value(&mut key.custom[step], name);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(tonic: u8, scale: Scale) -> Key {
+        Key { tonic, scale, ..Key::default() }
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_note_in_the_key() {
+        let c_major = key(0, Scale::Major);
+        let snapped: Vec<i32> = (60..=72).map(|note| c_major.snap(note)).collect();
+        assert_eq!(snapped, [60, 60, 62, 62, 64, 65, 65, 67, 67, 69, 69, 71, 72]);
+        assert_eq!(key(2, Scale::Major).snap(61), 61);
+        assert_eq!(Key::default().snap(61), 61);
+    }
+
+    #[test]
+    fn counts_degrees_across_octaves() {
+        let c_major = key(0, Scale::Major);
+        assert_eq!(c_major.degree(4, 0), Pitch(60));
+        assert_eq!(c_major.degree(4, 7), Pitch(72));
+        assert_eq!(c_major.degree(4, -1), Pitch(59));
+    }
+
+    #[test]
+    fn rekeying_keeps_scale_degrees() {
+        let c_major = key(0, Scale::Major);
+        let rekeyed = |to: &Key| -> Vec<u8> {
+            [60, 62, 64, 65, 67, 69, 71].into_iter().map(|note| c_major.rekey(Pitch(note), to).0).collect()
+        };
+        assert_eq!(rekeyed(&key(2, Scale::Major)), [62, 64, 66, 67, 69, 71, 73]);
+        assert_eq!(rekeyed(&key(0, Scale::Minor)), [60, 62, 63, 65, 67, 68, 70]);
+        // Tonics more than a tritone up are reached by going down.
+        assert_eq!(rekeyed(&key(10, Scale::Major)), [58, 60, 62, 63, 65, 67, 69]);
+    }
+}
@@ -0,0 +1,486 @@
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::simulation::{Object, Pitch, SceneObjects};
+use crate::synth::Voice;
+use crate::transport::TimeSignature;
+use crate::tuning::Tuning;
+
+/// Every MessagePack scene file written by horizons starts with these bytes, followed by the
+/// format version as a little-endian `u32` and then the encoded `SceneFile`.
+pub const MAGIC: &[u8; 4] = b"HRZN";
+
+/// Bump this whenever a change to `SceneFile` (or anything it contains) cannot be read by the
+/// previous version's deserializer, and add a migration step to `decode_body`.
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct SceneMetadata {
+    pub title: String,
+    pub author: String,
+    pub tempo: f32,
+    /// Seconds since the unix epoch, or 0 if unknown (e.g. for migrated saves).
+    pub created: u64,
+    pub modified: u64,
+    /// How the steps pegs store are tuned. Scenes saved before tunings existed are 12-TET.
+    #[serde(default)]
+    pub tuning: Tuning,
+    /// Scenes saved before time signatures existed are in 4/4.
+    #[serde(default)]
+    pub time_signature: TimeSignature,
+}
+
+impl Default for SceneMetadata {
+    fn default() -> Self {
+        SceneMetadata {
+            title: String::new(),
+            author: String::new(),
+            tempo: 120.,
+            created: 0,
+            modified: 0,
+            tuning: Tuning::default(),
+            time_signature: TimeSignature::default(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SceneFile {
+    pub metadata: SceneMetadata,
+    pub scene: SceneObjects,
+}
+
+/// The text formats have no binary header, so the version is stored alongside the file.
+#[derive(Serialize, Deserialize)]
+struct TextDocument<T> {
+    format_version: u32,
+    file: T,
+}
+
+#[derive(Deserialize)]
+struct TextVersion {
+    format_version: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SceneCodec {
+    MessagePack,
+    Ron,
+    Json,
+}
+
+impl SceneCodec {
+    pub const ALL: [SceneCodec; 3] = [SceneCodec::MessagePack, SceneCodec::Ron, SceneCodec::Json];
+
+    /// Anything that isn't `.ron` or `.json`, including extensionless legacy saves, is MessagePack.
+    pub fn from_file_name(file_name: &str) -> Self {
+        let extension = file_name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ron") => SceneCodec::Ron,
+            Some("json") => SceneCodec::Json,
+            _ => SceneCodec::MessagePack,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SceneCodec::MessagePack => "horizons",
+            SceneCodec::Ron => "ron",
+            SceneCodec::Json => "json",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SceneCodec::MessagePack => "Horizons scene",
+            SceneCodec::Ron => "RON",
+            SceneCodec::Json => "JSON",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    UnknownVersion(u32),
+    Corrupt(String),
+    NoteOutOfRange { object: u32, note: u8 },
+    EmptyPeg { object: u32 },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::UnknownVersion(version) => write!(
+                f,
+                "scene format version {} is newer than this build supports (up to {})",
+                version, FORMAT_VERSION
+            ),
+            SceneError::Corrupt(reason) => write!(f, "scene data is corrupt: {}", reason),
+            SceneError::NoteOutOfRange { object, note } => write!(
+                f,
+                "peg {} plays MIDI note {}, but MIDI notes only go up to {}",
+                object, note, Pitch::MAX.0
+            ),
+            SceneError::EmptyPeg { object } => write!(f, "peg {} has no notes", object),
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for SceneError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        SceneError::Corrupt(e.to_string())
+    }
+}
+
+impl From<ron::error::SpannedError> for SceneError {
+    fn from(e: ron::error::SpannedError) -> Self {
+        SceneError::Corrupt(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SceneError {
+    fn from(e: serde_json::Error) -> Self {
+        SceneError::Corrupt(e.to_string())
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn encode(file: &SceneFile, codec: SceneCodec) -> Vec<u8> {
+    match codec {
+        SceneCodec::MessagePack => {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+            // Named encoding keeps struct fields keyed by name, so adding a `#[serde(default)]`
+            // field does not require a version bump.
+            bytes.extend(rmp_serde::to_vec_named(file).expect("scene should always be serializable"));
+            bytes
+        }
+        SceneCodec::Ron => {
+            let document = TextDocument { format_version: FORMAT_VERSION, file };
+            ron::ser::to_string_pretty(&document, ron::ser::PrettyConfig::default())
+                .expect("scene should always be serializable")
+                .into_bytes()
+        }
+        SceneCodec::Json => {
+            let document = TextDocument { format_version: FORMAT_VERSION, file };
+            serde_json::to_vec_pretty(&document).expect("scene should always be serializable")
+        }
+    }
+}
+
+/// Decodes, migrates and validates a scene file. Anything that would panic or misbehave once
+/// spawned or played, such as pegs without notes, empty rhythms or negative and non-finite sizes
+/// and times, is rejected with an error instead.
+pub fn decode(bytes: &[u8], codec: SceneCodec) -> Result<SceneFile, SceneError> {
+    let file = match codec {
+        SceneCodec::MessagePack => match bytes.strip_prefix(MAGIC) {
+            Some(rest) => {
+                if rest.len() < 4 {
+                    return Err(SceneError::Corrupt("truncated header".into()));
+                }
+                let (version, body) = rest.split_at(4);
+                let version = u32::from_le_bytes(version.try_into().unwrap());
+                decode_body(version, Body::MessagePack(body))?
+            }
+            // Saves from before the container existed are a bare `SceneObjects`.
+            None => decode_body(0, Body::MessagePack(bytes))?,
+        },
+        SceneCodec::Ron => {
+            let text = std::str::from_utf8(bytes).map_err(|e| SceneError::Corrupt(e.to_string()))?;
+            let TextVersion { format_version } = ron::from_str(text)?;
+            decode_body(format_version, Body::Ron(text))?
+        }
+        SceneCodec::Json => {
+            let TextVersion { format_version } = serde_json::from_slice(bytes)?;
+            decode_body(format_version, Body::Json(bytes))?
+        }
+    };
+    validate(&file.scene)?;
+    file.metadata.tuning.validate().map_err(SceneError::Corrupt)?;
+    let TimeSignature { beats, unit } = file.metadata.time_signature;
+    if beats == 0 {
+        return Err(SceneError::Corrupt("the time signature has no beats".into()));
+    }
+    // MIDI files can only store powers of two.
+    if !TimeSignature::UNITS.contains(&unit) {
+        return Err(SceneError::Corrupt(format!("{} is not a note value a time signature can count in", unit)));
+    }
+    if !(file.metadata.tempo.is_finite() && file.metadata.tempo > 0.) {
+        return Err(SceneError::Corrupt("the tempo must be a positive number of beats per minute".into()));
+    }
+    Ok(file)
+}
+
+/// A file body whose version is known, waiting to be parsed as that version's schema.
+enum Body<'a> {
+    MessagePack(&'a [u8]),
+    Ron(&'a str),
+    Json(&'a [u8]),
+}
+
+impl Body<'_> {
+    fn parse<T: DeserializeOwned>(&self) -> Result<T, SceneError> {
+        Ok(match self {
+            Body::MessagePack(bytes) => rmp_serde::from_slice(bytes)?,
+            Body::Ron(text) => ron::from_str::<TextDocument<T>>(text)?.file,
+            Body::Json(bytes) => serde_json::from_slice::<TextDocument<T>>(bytes)?.file,
+        })
+    }
+}
+
+/// Parses a file body written with format `version` and upgrades it to the current schema.
+fn decode_body(version: u32, body: Body) -> Result<SceneFile, SceneError> {
+    match version {
+        0 => Ok(v1::migrate(body.parse::<v0::SceneObjects>()?.into())),
+        1 => Ok(v1::migrate(body.parse()?)),
+        FORMAT_VERSION => body.parse(),
+        _ => Err(SceneError::UnknownVersion(version)),
+    }
+}
+
+fn validate(scene: &SceneObjects) -> Result<(), SceneError> {
+    for (&id, object) in scene.objects.iter() {
+        if id >= scene.object_count {
+            return Err(SceneError::Corrupt(format!(
+                "object id {} is not below the object count {}",
+                id, scene.object_count
+            )));
+        }
+        let corrupt = |reason: String| SceneError::Corrupt(format!("{} {}: {}", object.kind_name(), id, reason));
+        if !object.position().is_finite() {
+            return Err(corrupt("its position is not a number".into()));
+        }
+        if let Some((notes, ..)) = object.sound() {
+            if notes.is_empty() {
+                return Err(SceneError::EmptyPeg { object: id });
+            }
+            if let Some(&Pitch(note)) = notes.iter().find(|&&note| note > Pitch::MAX) {
+                return Err(SceneError::NoteOutOfRange { object: id, note });
+            }
+        }
+        if let Some((_, Voice::Synth(patch), ..)) = object.sound() {
+            patch.validate().map_err(corrupt)?;
+        }
+        match object {
+            Object::BallSpawner { pattern, .. } => pattern.validate().map_err(corrupt)?,
+            Object::Wall { points, restitution, friction } => {
+                if points.len() < 2 {
+                    return Err(corrupt("it has fewer than two points".into()));
+                }
+                if !points.iter().all(|point| Vec2::from(*point).is_finite()) {
+                    return Err(corrupt("its points are not numbers".into()));
+                }
+                if !(restitution.is_finite() && *restitution >= 0. && friction.is_finite() && *friction >= 0.) {
+                    return Err(corrupt("its bounce and friction must be zero or more".into()));
+                }
+            }
+            Object::Bar { length, angle, .. } if !(length.is_finite() && *length > 0. && angle.is_finite()) => {
+                return Err(corrupt("its length must be positive and its angle a number".into()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Version 1 has the same layout as version 2, but pegs stored note indices (0 = C3, 24 = C5)
+/// instead of MIDI note numbers.
+mod v1 {
+    use crate::simulation::{Object, Pitch};
+    use super::SceneFile;
+
+    /// MIDI note number of note index 0.
+    const C3: u8 = 48;
+
+    pub fn migrate(mut file: SceneFile) -> SceneFile {
+        for object in file.scene.objects.values_mut() {
+            if let Object::Peg { notes, .. } = object {
+                for Pitch(note) in notes.iter_mut() {
+                    // Indices that were already out of range are left for `validate` to reject.
+                    *note = note.saturating_add(C3);
+                }
+            }
+        }
+        file
+    }
+}
+
+/// The layout `SceneObjects` was saved in before it was wrapped in a `SceneFile`.
+mod v0 {
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use crate::simulation::{self, Pitch};
+    use crate::instruments::InstrumentId;
+    use crate::sequences::PlayMode;
+    use crate::spawners::SpawnPattern;
+    use crate::synth::Voice;
+    use super::{SceneFile, SceneMetadata};
+
+    #[derive(Deserialize)]
+    pub struct SceneObjects {
+        objects: BTreeMap<u32, Object>,
+        object_count: u32,
+    }
+
+    #[derive(Deserialize)]
+    enum Object {
+        Peg(Peg),
+        Ball(f32, f32),
+        BallSpawner(f32, f32),
+    }
+
+    /// The earliest saves, like the `savefile` in the repository, stored pegs without notes;
+    /// those always played C3.
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Peg {
+        WithNotes(f32, f32, Vec<Pitch>),
+        WithoutNotes(f32, f32),
+    }
+
+    impl From<Object> for simulation::Object {
+        fn from(object: Object) -> Self {
+            match object {
+                Object::Peg(Peg::WithNotes(x, y, notes)) => simulation::Object::Peg {
+                    x,
+                    y,
+                    notes,
+                    voice: Voice::default(),
+                    instrument: InstrumentId::default(),
+                    mode: PlayMode::default(),
+                },
+                Object::Peg(Peg::WithoutNotes(x, y)) => simulation::Object::Peg {
+                    x,
+                    y,
+                    notes: vec![Pitch(0)],
+                    voice: Voice::default(),
+                    instrument: InstrumentId::default(),
+                    mode: PlayMode::default(),
+                },
+                Object::Ball(x, y) => simulation::Object::Ball { x, y, spawner: None, velocity: [0.; 2] },
+                Object::BallSpawner(x, y) => simulation::Object::BallSpawner { x, y, pattern: SpawnPattern::default() },
+            }
+        }
+    }
+
+    impl From<SceneObjects> for SceneFile {
+        fn from(scene: SceneObjects) -> Self {
+            SceneFile {
+                metadata: SceneMetadata::default(),
+                scene: simulation::SceneObjects {
+                    objects: scene.objects.into_iter().map(|(id, object)| (id, object.into())).collect(),
+                    object_count: scene.object_count,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drums::Drum;
+    use crate::spawners::{Rhythm, SpawnPattern};
+    use std::collections::BTreeMap;
+
+    fn example() -> SceneFile {
+        let mut objects = BTreeMap::new();
+        objects.insert(0, Object::BallSpawner {
+            x: 0.,
+            y: 200.,
+            pattern: SpawnPattern {
+                rhythm: Rhythm::Euclidean { pulses: 3, steps: 8, step: 0.5, rotation: 1 },
+                ..SpawnPattern::default()
+            },
+        });
+        objects.insert(2, Object::Drum { x: -50., y: 0., sound: Drum::Clap });
+        objects.insert(3, Object::Wall { points: vec![[0., -100.], [100., -150.]], restitution: 0.5, friction: 0. });
+        let metadata = SceneMetadata {
+            title: "Example".into(),
+            tempo: 90.,
+            time_signature: TimeSignature { beats: 3, unit: 8 },
+            ..SceneMetadata::default()
+        };
+        SceneFile { metadata, scene: SceneObjects { objects, object_count: 4 } }
+    }
+
+    fn peg_notes(file: &SceneFile) -> Vec<Vec<Pitch>> {
+        file.scene.objects.values().filter_map(|object| object.sound()).map(|(notes, ..)| notes.clone()).collect()
+    }
+
+    #[test]
+    fn round_trips_through_every_codec() {
+        let file = example();
+        for codec in SceneCodec::ALL {
+            let decoded = decode(&encode(&file, codec), codec).unwrap();
+            assert!(decoded.scene.objects == file.scene.objects, "{:?} changed the objects", codec);
+            assert_eq!(decoded.scene.object_count, file.scene.object_count);
+            assert_eq!(decoded.metadata.title, "Example");
+            assert_eq!(decoded.metadata.tempo, 90.);
+            assert_eq!(decoded.metadata.time_signature, TimeSignature { beats: 3, unit: 8 });
+        }
+    }
+
+    #[test]
+    fn migrates_saves_from_before_the_container() {
+        // Pegs were bare positions, which played C3.
+        let file = decode(include_bytes!("../savefile"), SceneCodec::MessagePack).unwrap();
+        assert_eq!(file.scene.objects.len(), 4);
+        assert_eq!(peg_notes(&file), vec![vec![Pitch(48)]; 4]);
+        // Then they gained note indices counted from C3.
+        let file = decode(include_bytes!("../3"), SceneCodec::MessagePack).unwrap();
+        assert!(file.scene.objects.values().any(|object| matches!(object, Object::BallSpawner { .. })));
+        assert!(peg_notes(&file).iter().flatten().all(|&note| note >= Pitch(48)));
+    }
+
+    #[test]
+    fn migrates_version_1_note_indices() {
+        let json = r#"{"format_version": 1, "file": {
+            "metadata": {"title": "", "author": "", "tempo": 120.0, "created": 0, "modified": 0},
+            "scene": {"objects": {"0": {"Peg": {"x": 0, "y": 0, "notes": [0, 24]}}}, "object_count": 1}
+        }}"#;
+        let file = decode(json.as_bytes(), SceneCodec::Json).unwrap();
+        assert_eq!(peg_notes(&file), [[Pitch(48), Pitch(72)]]);
+    }
+
+    #[test]
+    fn reads_hand_written_text() {
+        let ron = r#"(format_version: 2, file: (
+            metadata: (title: "Ron", author: "", tempo: 100.0, created: 0, modified: 0),
+            scene: (objects: {0: Peg(x: 0.0, y: 0.0, notes: [60, 64])}, object_count: 1),
+        ))"#;
+        let file = decode(ron.as_bytes(), SceneCodec::Ron).unwrap();
+        assert_eq!(file.metadata.title, "Ron");
+        assert_eq!(peg_notes(&file), [[Pitch(60), Pitch(64)]]);
+        let json = r#"{"format_version": 2, "file": {
+            "metadata": {"title": "Json", "author": "", "tempo": 100.0, "created": 0, "modified": 0},
+            "scene": {"objects": {"0": {"Peg": {"x": 0, "y": 0, "notes": [60, 64]}}}, "object_count": 1}
+        }}"#;
+        let file = decode(json.as_bytes(), SceneCodec::Json).unwrap();
+        assert_eq!(file.metadata.title, "Json");
+        assert_eq!(peg_notes(&file), [[Pitch(60), Pitch(64)]]);
+    }
+
+    #[test]
+    fn rejects_time_signatures_midi_cannot_store() {
+        for unit in [0, 3, 12] {
+            let mut file = example();
+            file.metadata.time_signature.unit = unit;
+            let result = decode(&encode(&file, SceneCodec::Json), SceneCodec::Json);
+            assert!(matches!(result, Err(SceneError::Corrupt(_))), "a unit of {} was accepted", unit);
+        }
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let json = br#"{"format_version": 99, "file": null}"#;
+        assert!(matches!(decode(json, SceneCodec::Json), Err(SceneError::UnknownVersion(99))));
+    }
+}
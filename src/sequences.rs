@@ -0,0 +1,277 @@
+use bevy::prelude::*;
+use bevy_egui::egui;
+use serde::{Deserialize, Serialize};
+use crate::simulation::{NoteEvent, Pitch, ResetPerformance, StartPerformance, TIMESTEP};
+
+/// Lets a peg step through its notes one hit at a time, or play them as an arpeggio, instead of
+/// sounding them all at once. Added by `SimulationPlugin`, so headless runs sequence the same way.
+pub struct SequencePlugin;
+
+impl Plugin for SequencePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Arpeggios>()
+            .add_systems(FixedUpdate, reset_sequences)
+            .add_systems(FixedUpdate, play_arpeggios);
+    }
+}
+
+/// How a peg plays its notes when it is hit.
+#[derive(Component, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum PlayMode {
+    /// Every note at once.
+    #[default]
+    Chord,
+    /// One note per hit, moving through the notes in `order`.
+    Sequence { order: Order },
+    /// Every note one after another, `rate` notes per second.
+    Arpeggio { order: Order, rate: f32 },
+}
+
+impl PlayMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayMode::Chord => "Chord",
+            PlayMode::Sequence { .. } => "Sequence",
+            PlayMode::Arpeggio { .. } => "Arpeggio",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Order {
+    /// In the order they're listed, starting over after the last.
+    #[default]
+    Forward,
+    /// Up the list and back down again, without repeating the ends.
+    PingPong,
+    /// Any order, though never the same note twice in a row.
+    Random,
+}
+
+impl Order {
+    pub const ALL: [Order; 3] = [Order::Forward, Order::PingPong, Order::Random];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Order::Forward => "Forward",
+            Order::PingPong => "Ping-pong",
+            Order::Random => "Random",
+        }
+    }
+}
+
+/// Where a peg is in its sequence. Every performance starts from the same state, so a scene plays
+/// the same each time, random order included.
+#[derive(Component)]
+pub struct SequenceState {
+    /// How many notes the sequence has played.
+    position: usize,
+    seed: u32,
+    rng: u32,
+    last: Option<usize>,
+}
+
+impl SequenceState {
+    /// Each peg gets its own random order, seeded by its object id.
+    pub fn new(id: u32) -> Self {
+        let seed = id.wrapping_mul(0x9e37_79b9) | 1;
+        SequenceState { position: 0, seed, rng: seed, last: None }
+    }
+
+    fn reset(&mut self) {
+        *self = SequenceState { position: 0, seed: self.seed, rng: self.seed, last: None };
+    }
+
+    /// A xorshift generator, so runs are repeatable without pulling in a dependency.
+    fn random(&mut self, below: usize) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as usize % below
+    }
+
+    /// The index of the next note out of `count` in `order`.
+    fn next(&mut self, count: usize, order: Order) -> usize {
+        let index = match order {
+            Order::Forward => self.position % count,
+            Order::PingPong if count > 1 => {
+                let period = 2 * count - 2;
+                let step = self.position % period;
+                if step < count { step } else { period - step }
+            }
+            Order::PingPong => 0,
+            Order::Random if count > 1 => match self.last {
+                Some(last) => {
+                    let index = self.random(count - 1);
+                    if index >= last { index + 1 } else { index }
+                }
+                None => self.random(count),
+            },
+            Order::Random => 0,
+        };
+        self.position += 1;
+        self.last = Some(index);
+        index
+    }
+
+    /// The notes one hit plays, in the order they sound. Only arpeggios return more than one
+    /// group; the rest follow the first at the arpeggio's rate.
+    pub fn hit(&mut self, notes: &[Pitch], mode: &PlayMode) -> Vec<Vec<Pitch>> {
+        if notes.is_empty() {
+            return Vec::new();
+        }
+        match *mode {
+            PlayMode::Chord => vec![notes.to_vec()],
+            PlayMode::Sequence { order } => vec![vec![notes[self.next(notes.len(), order)]]],
+            PlayMode::Arpeggio { order, .. } => {
+                // Every arpeggio starts from the top of its order, though random ones still differ.
+                self.position = 0;
+                self.last = None;
+                let length = match order {
+                    Order::PingPong if notes.len() > 1 => 2 * notes.len() - 1,
+                    _ => notes.len(),
+                };
+                (0..length).map(|_| vec![notes[self.next(notes.len(), order)]]).collect()
+            }
+        }
+    }
+}
+
+/// Notes of arpeggios still to play, with the time in seconds each is due.
+#[derive(Resource, Default)]
+pub struct Arpeggios(Vec<(f64, NoteEvent)>);
+
+impl Arpeggios {
+    /// Queues the rest of an arpeggio started at `now`, replacing whatever was left of the last
+    /// one the same peg played.
+    pub fn start(&mut self, now: f64, rate: f32, notes: Vec<Vec<Pitch>>, hit: &NoteEvent) {
+        self.0.retain(|(_, note_event)| note_event.peg != hit.peg);
+        let interval = 1. / rate.max(0.1) as f64;
+        for (i, notes) in notes.into_iter().enumerate() {
+            let note_event = NoteEvent { notes, hit: false, ..hit.clone() };
+            self.0.push((now + (i + 1) as f64 * interval, note_event));
+        }
+    }
+}
+
+fn reset_sequences(
+    mut start_events: EventReader<StartPerformance>,
+    mut reset_events: EventReader<ResetPerformance>,
+    mut states: Query<&mut SequenceState>,
+    mut arpeggios: ResMut<Arpeggios>,
+) {
+    if start_events.read().count() + reset_events.read().count() == 0 {
+        return;
+    }
+    for mut state in states.iter_mut() {
+        state.reset();
+    }
+    arpeggios.0.clear();
+}
+
+/// Sends each queued arpeggio note on the tick closest to when it is due.
+pub fn play_arpeggios(time: Res<Time>, mut arpeggios: ResMut<Arpeggios>, mut note_event_writer: EventWriter<NoteEvent>) {
+    let now = time.elapsed_seconds_f64() + TIMESTEP as f64 / 2.;
+    arpeggios.0.retain(|(due, note_event)| {
+        if *due > now {
+            return true;
+        }
+        note_event_writer.send(note_event.clone());
+        false
+    });
+}
+
+/// Edits how a peg plays its notes.
+pub fn play_mode_editor(ui: &mut egui::Ui, mode: &mut PlayMode) {
+    egui::ComboBox::from_label("Play as")
+        .selected_text(mode.name())
+        .show_ui(ui, |ui| {
+            let order = match *mode {
+                PlayMode::Chord => Order::default(),
+                PlayMode::Sequence { order } | PlayMode::Arpeggio { order, .. } => order,
+            };
+            let rate = match *mode {
+                PlayMode::Arpeggio { rate, .. } => rate,
+                _ => 8.,
+            };
+            for candidate in [PlayMode::Chord, PlayMode::Sequence { order }, PlayMode::Arpeggio { order, rate }] {
+                if ui.selectable_label(mode.name() == candidate.name(), candidate.name()).clicked() {
+                    *mode = candidate;
+                }
+            }
+        });
+    let (PlayMode::Sequence { order } | PlayMode::Arpeggio { order, .. }) = mode else { return };
+    egui::ComboBox::from_label("Order")
+        .selected_text(order.name())
+        .show_ui(ui, |ui| {
+            for candidate in Order::ALL {
+                ui.selectable_value(order, candidate, candidate.name());
+            }
+        });
+    if let PlayMode::Arpeggio { rate, .. } = mode {
+        ui.add(egui::Slider::new(rate, 1.0..=32.).logarithmic(true).text("Rate").suffix(" notes/s"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruments::InstrumentId;
+    use crate::synth::Voice;
+
+    const NOTES: [Pitch; 3] = [Pitch(60), Pitch(64), Pitch(67)];
+
+    fn sequence(state: &mut SequenceState, order: Order, hits: usize) -> Vec<u8> {
+        (0..hits).map(|_| state.hit(&NOTES, &PlayMode::Sequence { order })[0][0].0).collect()
+    }
+
+    #[test]
+    fn sequences_step_through_their_order() {
+        assert_eq!(sequence(&mut SequenceState::new(1), Order::Forward, 4), [60, 64, 67, 60]);
+        assert_eq!(sequence(&mut SequenceState::new(1), Order::PingPong, 6), [60, 64, 67, 64, 60, 64]);
+        assert_eq!(SequenceState::new(1).hit(&NOTES, &PlayMode::Chord), [NOTES.to_vec()]);
+    }
+
+    #[test]
+    fn random_orders_repeat_for_the_same_peg() {
+        let first = sequence(&mut SequenceState::new(7), Order::Random, 32);
+        assert_eq!(sequence(&mut SequenceState::new(7), Order::Random, 32), first);
+        assert!(first.windows(2).all(|pair| pair[0] != pair[1]), "a note played twice in a row");
+        assert_ne!(sequence(&mut SequenceState::new(8), Order::Random, 32), first);
+        // Resetting for a new performance starts the same order over.
+        let mut state = SequenceState::new(7);
+        sequence(&mut state, Order::Random, 5);
+        state.reset();
+        assert_eq!(sequence(&mut state, Order::Random, 32), first);
+    }
+
+    #[test]
+    fn arpeggios_play_every_note_from_the_start() {
+        let mode = PlayMode::Arpeggio { order: Order::PingPong, rate: 8. };
+        let mut state = SequenceState::new(1);
+        let expected: Vec<Vec<Pitch>> = [60, 64, 67, 64, 60].into_iter().map(|note| vec![Pitch(note)]).collect();
+        assert_eq!(state.hit(&NOTES, &mode), expected);
+        assert_eq!(state.hit(&NOTES, &mode), expected);
+    }
+
+    #[test]
+    fn arpeggios_replace_the_rest_of_the_last_one() {
+        let hit = NoteEvent {
+            peg: Entity::from_raw(1),
+            notes: vec![Pitch(60)],
+            voice: Voice::default(),
+            instrument: InstrumentId::default(),
+            speed: 100.,
+            position: Vec2::ZERO,
+            spawner: None,
+            hit: true,
+        };
+        let mut arpeggios = Arpeggios::default();
+        arpeggios.start(0., 4., vec![vec![Pitch(64)], vec![Pitch(67)]], &hit);
+        arpeggios.start(1., 2., vec![vec![Pitch(72)]], &hit);
+        let queued: Vec<(f64, Vec<Pitch>, bool)> =
+            arpeggios.0.iter().map(|(due, note_event)| (*due, note_event.notes.clone(), note_event.hit)).collect();
+        assert_eq!(queued, [(1.5, vec![Pitch(72)], false)]);
+    }
+}
@@ -0,0 +1,616 @@
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::bars::{bar_body, Bar};
+use crate::drums::Drum;
+use crate::instruments::InstrumentId;
+use crate::scene::{SceneFile, SceneMetadata};
+use crate::sequences::{play_arpeggios, Arpeggios, PlayMode, SequencePlugin, SequenceState};
+use crate::spawners::SpawnPattern;
+use crate::synth::Voice;
+use crate::transport::{advance_transport, Transport, TransportPlugin};
+use crate::walls::{wall_body, Wall};
+
+/// The physics and note-triggering core of horizons. It needs no window, renderer or audio
+/// device; `PegPlugin` and `SoundPlugin` add sprites, input and playback on top of it.
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
+            .add_plugins(SequencePlugin)
+            .add_plugins(TransportPlugin)
+            .insert_resource(SceneObjects { objects: BTreeMap::new(), object_count: 0 })
+            .insert_resource(Performance { started: false })
+            .init_resource::<SceneMetadata>()
+            .add_event::<SpawnObject>()
+            .add_event::<ObjectAdded>()
+            .add_event::<DeleteObjects>()
+            .add_event::<DespawnObject>()
+            .add_event::<StartPerformance>()
+            .add_event::<ResetPerformance>()
+            .add_event::<NoteEvent>()
+            .add_systems(Startup, setup_physics)
+            .add_systems(FixedUpdate, delete_all_objects)
+            .add_systems(FixedUpdate, despawn_object.before(spawn_object))
+            .add_systems(FixedUpdate, spawn_object)
+            .add_systems(FixedUpdate, (reset_performance, start_performance, emit_balls).chain().after(advance_transport).before(spawn_object))
+            .add_systems(FixedUpdate, despawn_fallen_balls)
+            .add_systems(FixedUpdate, detect_note_hits.before(play_arpeggios))
+            .add_systems(PostUpdate, remember_velocity.before(PhysicsSet::SyncBackend));
+    }
+}
+
+/// Length of one physics step, in seconds.
+pub const TIMESTEP: f32 = 1. / 64.;
+
+/// Builds an app that runs the simulation without a window, advancing exactly one `TIMESTEP`
+/// per call to `update`. The scene is spawned by the first call.
+pub fn headless_app(file: &SceneFile) -> App {
+    let mut app = App::new();
+    app
+        .add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin, SimulationPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(TIMESTEP)));
+    // Time doesn't advance on the very first update, so get it out of the way here.
+    app.update();
+    app.world.insert_resource(file.metadata.clone());
+    app.world.insert_resource(file.scene.clone());
+    for (id, object) in file.scene.objects.iter() {
+        app.world.send_event(SpawnObject(object.clone(), Some(*id)));
+    }
+    app
+}
+
+fn setup_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.timestep_mode = TimestepMode::Fixed { dt: TIMESTEP, substeps: 1 };
+}
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct SceneObjects {
+    pub objects: BTreeMap<u32, Object>,
+    pub(crate) object_count: u32
+}
+
+#[derive(Resource, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Object {
+    Peg {
+        x: f32,
+        y: f32,
+        notes: Vec<Pitch>,
+        #[serde(default)]
+        voice: Voice,
+        #[serde(default)]
+        instrument: InstrumentId,
+        #[serde(default)]
+        mode: PlayMode,
+    },
+    /// A percussion peg, which plays a drum instead of notes.
+    Drum { x: f32, y: f32, sound: Drum },
+    /// `spawner` is the id of the spawner that dropped the ball, if any.
+    Ball {
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        spawner: Option<u32>,
+        #[serde(default)]
+        velocity: [f32; 2],
+    },
+    BallSpawner {
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        pattern: SpawnPattern,
+    },
+    /// A xylophone-style bar centred on `x` and `y`, which plays like a peg.
+    Bar {
+        x: f32,
+        y: f32,
+        length: f32,
+        /// Degrees anticlockwise from horizontal.
+        angle: f32,
+        notes: Vec<Pitch>,
+        voice: Voice,
+        instrument: InstrumentId,
+        mode: PlayMode,
+        /// Whether the notes are laid out along the bar from one end to the other, so a ball
+        /// plays the one it lands on instead of all of them.
+        #[serde(default)]
+        split: bool,
+    },
+    /// A silent line segment or polyline through at least two `points`.
+    Wall {
+        points: Vec<[f32; 2]>,
+        /// How much of a ball's speed the wall gives back; above 1 makes a bumper.
+        restitution: f32,
+        /// Relative to a peg's, so 1 grips like a peg and 0 is frictionless.
+        friction: f32,
+    },
+}
+
+impl Object {
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Object::Peg { .. } => "peg",
+            Object::Drum { .. } => "drum",
+            Object::Ball { .. } => "ball",
+            Object::BallSpawner { .. } => "spawner",
+            Object::Bar { .. } => "bar",
+            Object::Wall { .. } => "wall",
+        }
+    }
+
+    pub fn position(&self) -> Vec2 {
+        match *self {
+            Object::Peg { x, y, .. }
+            | Object::Drum { x, y, .. }
+            | Object::Ball { x, y, .. }
+            | Object::BallSpawner { x, y, .. }
+            | Object::Bar { x, y, .. } => Vec2::new(x, y),
+            Object::Wall { ref points, .. } => Vec2::from(points[0]),
+        }
+    }
+
+    /// The height of the object's lowest point.
+    pub fn bottom(&self) -> f32 {
+        match self {
+            Object::Wall { points, .. } => points.iter().map(|point| point[1]).fold(f32::INFINITY, f32::min),
+            &Object::Bar { y, length, angle, .. } => y - (length / 2. * angle.to_radians().sin()).abs(),
+            _ => self.position().y,
+        }
+    }
+
+    /// The notes of a peg or bar, and how it plays them.
+    pub fn sound(&self) -> Option<(&Vec<Pitch>, &Voice, &InstrumentId, &PlayMode)> {
+        match self {
+            Object::Peg { notes, voice, instrument, mode, .. } | Object::Bar { notes, voice, instrument, mode, .. } => {
+                Some((notes, voice, instrument, mode))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn sound_mut(&mut self) -> Option<(&mut Vec<Pitch>, &mut Voice, &mut InstrumentId, &mut PlayMode)> {
+        match self {
+            Object::Peg { notes, voice, instrument, mode, .. } | Object::Bar { notes, voice, instrument, mode, .. } => {
+                Some((notes, voice, instrument, mode))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Spawns an object's entity. Objects without an id are new: they are given one and added to
+/// `SceneObjects` (balls are never stored).
+#[derive(Event)]
+pub struct SpawnObject(pub Object, pub Option<u32>);
+
+/// Sent when a new object has been added to `SceneObjects` by `SpawnObject`.
+#[derive(Event)]
+pub struct ObjectAdded {
+    pub id: u32,
+    pub object: Object,
+}
+
+/// Whether the balls have been dropped from the spawners. `Transport` counts the beats since Start.
+#[derive(Resource)]
+pub struct Performance {
+    pub started: bool,
+}
+
+/// Drops a ball from every spawner.
+#[derive(Event)]
+pub struct StartPerformance;
+
+/// Removes every ball.
+#[derive(Event)]
+pub struct ResetPerformance;
+
+/// A ball hit a peg, which should play `notes`.
+#[derive(Event, Clone)]
+pub struct NoteEvent {
+    pub peg: Entity,
+    pub notes: Vec<Pitch>,
+    pub voice: Voice,
+    pub instrument: InstrumentId,
+    /// How fast the ball was closing on the peg when they touched, in pixels per second. Only
+    /// the speed along the contact normal counts, so a ball rolling along a peg hits it softly.
+    pub speed: f32,
+    /// Where the peg is, for panning.
+    pub position: Vec2,
+    /// The spawner that dropped the ball, if it didn't come from a right click.
+    pub spawner: Option<u32>,
+    /// Whether a ball just hit the peg, as opposed to this being a later note of an arpeggio.
+    pub hit: bool,
+}
+
+/// Anything balls bounce off to play a sound, including percussion pegs.
+#[derive(Component)]
+pub struct Peg;
+
+/// Marks the pegs that are drums.
+#[derive(Component)]
+pub struct Percussion;
+
+#[derive(Component)]
+pub struct Ball {
+    pub spawner: Option<u32>,
+}
+
+/// A ball's velocity going into the latest physics step, before any bounce it took.
+#[derive(Component, Default)]
+pub struct VelocityBeforeStep(Vec2);
+
+#[derive(Component)]
+pub struct BallSpawner;
+
+#[derive(Component)]
+pub struct ObjectId(pub u32);
+
+#[derive(Component)]
+pub struct NotesList(pub Vec<Pitch>);
+
+/// A note as a step of the scene's `Tuning`. In the default 12-TET tuning these are MIDI note
+/// numbers, where 60 is middle C (C4); other tunings number their steps the same way, from 0 to 127.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Pitch(pub u8);
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+impl Pitch {
+    pub const MAX: Pitch = Pitch(127);
+
+    /// The note's frequency in 12-TET. Use `Tuning::frequency` for the frequency it plays at.
+    pub fn frequency(self) -> f32 {
+        440. * 2f32.powf((self.0 as f32 - 69.) / 12.)
+    }
+}
+
+impl fmt::Display for Pitch {
+    /// Scientific pitch notation, e.g. `C#4`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", NOTE_NAMES[self.0 as usize % 12], self.0 as i32 / 12 - 1)
+    }
+}
+
+impl FromStr for Pitch {
+    type Err = ();
+
+    /// Accepts a MIDI note number or a note name such as `C4`, `F#2`, `Bb-1` or `e5`.
+    fn from_str(text: &str) -> Result<Self, ()> {
+        if let Ok(number) = text.parse::<u8>() {
+            return (number <= Pitch::MAX.0).then_some(Pitch(number)).ok_or(());
+        }
+        let mut chars = text.chars();
+        let letter = chars.next().ok_or(())?.to_ascii_uppercase();
+        let mut semitone = match letter {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return Err(()),
+        };
+        let rest = chars.as_str();
+        let octave = if let Some(octave) = rest.strip_prefix('#') {
+            semitone += 1;
+            octave
+        } else if let Some(octave) = rest.strip_prefix('b') {
+            semitone -= 1;
+            octave
+        } else {
+            rest
+        };
+        let number = (octave.parse::<i32>().map_err(|_| ())? + 1) * 12 + semitone;
+        u8::try_from(number).ok().filter(|&number| number <= Pitch::MAX.0).map(Pitch).ok_or(())
+    }
+}
+
+/// How much of a ball's speed pegs and bars give back when it bounces off them.
+pub const PEG_RESTITUTION: f32 = 0.7;
+
+fn peg_body() -> impl Bundle {
+    (
+        RigidBody::Fixed,
+        Collider::ball(45.),
+        Restitution {
+            coefficient: PEG_RESTITUTION,
+            combine_rule: CoefficientCombineRule::Max,
+        },
+    )
+}
+
+fn object_transform(x: f32, y: f32) -> TransformBundle {
+    TransformBundle::from_transform(Transform {
+        translation: Vec3::new(x, y, 1.),
+        scale: Vec3::new(0.3, 0.3, 1.),
+        ..default()
+    })
+}
+
+pub fn spawn_object(
+    mut commands: Commands,
+    mut scene_objects: ResMut<SceneObjects>,
+    mut spawn_events: EventReader<SpawnObject>,
+    mut added_event_writer: EventWriter<ObjectAdded>,
+) {
+    for ev in spawn_events.read() {
+        match ev.0 {
+            Object::Peg { x, y, ref notes, ref voice, ref instrument, ref mode } => {
+                let id = ev.1.unwrap_or(scene_objects.object_count);
+                commands
+                    .spawn(object_transform(x, y))
+                    .insert(Peg)
+                    .insert(ObjectId(id))
+                    .insert(peg_body())
+                    .insert(NotesList(notes.clone()))
+                    .insert((mode.clone(), SequenceState::new(id)))
+                    .insert(voice.clone())
+                    .insert(instrument.clone());
+            }
+            Object::Drum { x, y, ref sound } => {
+                // Drums are sent as their General MIDI key, so MIDI output and export need no
+                // special case beyond the channel.
+                let id = ev.1.unwrap_or(scene_objects.object_count);
+                commands
+                    .spawn(object_transform(x, y))
+                    .insert((Peg, Percussion))
+                    .insert(ObjectId(id))
+                    .insert(peg_body())
+                    .insert(NotesList(vec![Pitch(sound.midi_note())]))
+                    .insert((PlayMode::Chord, SequenceState::new(id)))
+                    .insert(Voice::Drum(sound.clone()))
+                    .insert(InstrumentId::default());
+            }
+            Object::Ball { x, y, spawner, velocity } => {
+                commands
+                    .spawn(object_transform(x, y))
+                    .insert(Ball { spawner })
+                    .insert(GravityScale(2.0))
+                    .insert(RigidBody::Dynamic)
+                    .insert(Velocity::linear(Vec2::from(velocity)))
+                    .insert(VelocityBeforeStep::default())
+                    .insert(ActiveEvents::COLLISION_EVENTS)
+                    .insert(Collider::ball(45.));
+                continue;
+            }
+            Object::BallSpawner { x, y, ref pattern } => {
+                commands
+                    .spawn(object_transform(x, y))
+                    .insert((BallSpawner, pattern.clone()))
+                    .insert(ObjectId(ev.1.unwrap_or(scene_objects.object_count)));
+            }
+            Object::Bar { x, y, length, angle, ref notes, ref voice, ref instrument, ref mode, split } => {
+                let id = ev.1.unwrap_or(scene_objects.object_count);
+                let transform = Transform::from_xyz(x, y, 1.).with_rotation(Quat::from_rotation_z(angle.to_radians()));
+                commands
+                    .spawn(TransformBundle::from_transform(transform))
+                    .insert((Peg, Bar { length, split }))
+                    .insert(ObjectId(id))
+                    .insert(bar_body(length))
+                    .insert(NotesList(notes.clone()))
+                    .insert((mode.clone(), SequenceState::new(id)))
+                    .insert(voice.clone())
+                    .insert(instrument.clone());
+            }
+            Object::Wall { ref points, restitution, friction } => {
+                let [x, y] = points[0];
+                commands
+                    .spawn(TransformBundle::from_transform(Transform::from_xyz(x, y, 1.)))
+                    .insert(Wall)
+                    .insert(ObjectId(ev.1.unwrap_or(scene_objects.object_count)))
+                    .insert(wall_body(points, restitution, friction));
+            }
+        }
+        if ev.1.is_none() {
+            let id = scene_objects.object_count;
+            scene_objects.objects.insert(id, ev.0.clone());
+            scene_objects.object_count += 1;
+            added_event_writer.send(ObjectAdded { id, object: ev.0.clone() });
+        }
+    }
+}
+
+#[derive(Event)]
+pub struct DeleteObjects;
+
+/// Despawns the entity for a scene object without touching `SceneObjects`.
+#[derive(Event)]
+pub struct DespawnObject(pub u32);
+
+pub fn despawn_object(
+    mut despawn_events: EventReader<DespawnObject>,
+    query_objects: Query<(Entity, &ObjectId)>,
+    mut commands: Commands,
+) {
+    for DespawnObject(id) in despawn_events.read() {
+        for (e, ObjectId(obj_id)) in query_objects.iter() {
+            if obj_id == id {
+                commands.entity(e).despawn();
+            }
+        }
+    }
+}
+
+pub fn delete_all_objects(
+    mut delete_events: EventReader<DeleteObjects>,
+    query_all_objects: Query<Entity, Or<(With<Peg>, With<Ball>, With<BallSpawner>, With<Wall>)>>,
+    mut scene_objects: ResMut<SceneObjects>,
+    mut commands: Commands,
+) {
+    for _ in delete_events.read() {
+        for e in query_all_objects.iter() {
+            commands.entity(e).despawn();
+        }
+        scene_objects.objects.clear();
+        scene_objects.object_count = 0;
+    }
+}
+
+pub fn start_performance(
+    mut start_events: EventReader<StartPerformance>,
+    mut performance: ResMut<Performance>,
+    mut transport: ResMut<Transport>,
+) {
+    for _ in start_events.read() {
+        performance.started = true;
+        transport.start();
+    }
+}
+
+/// Drops balls from every spawner on the beats its pattern calls for, including the first beat on
+/// the tick Start is pressed.
+fn emit_balls(
+    performance: Res<Performance>,
+    transport: Res<Transport>,
+    query_ball_spawners: Query<(&Transform, &ObjectId, &SpawnPattern), With<BallSpawner>>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
+) {
+    if !performance.started {
+        return;
+    }
+    for (transform, ObjectId(id), pattern) in query_ball_spawners.iter() {
+        for _ in pattern.drops(transport.previous, transport.position) {
+            spawn_event_writer.send(SpawnObject(
+                Object::Ball {
+                    x: transform.translation.x,
+                    y: transform.translation.y,
+                    spawner: Some(*id),
+                    velocity: pattern.velocity().into(),
+                },
+                None,
+            ));
+        }
+    }
+}
+
+/// Balls this far below the lowest object can never hit anything again.
+const FALL_LIMIT: f32 = 2000.;
+
+/// Removes balls that have fallen past everything, so spawners that keep dropping balls don't
+/// pile up bodies forever.
+fn despawn_fallen_balls(
+    scene_objects: Res<SceneObjects>,
+    query_balls: Query<(Entity, &Transform), With<Ball>>,
+    mut commands: Commands,
+) {
+    let lowest = scene_objects.objects.values().map(Object::bottom).fold(0., f32::min);
+    for (e, transform) in query_balls.iter() {
+        if transform.translation.y < lowest - FALL_LIMIT {
+            commands.entity(e).despawn();
+        }
+    }
+}
+
+fn reset_performance(
+    mut reset_events: EventReader<ResetPerformance>,
+    mut performance: ResMut<Performance>,
+    query_balls: Query<Entity, With<Ball>>,
+    mut commands: Commands,
+) {
+    for _ in reset_events.read() {
+        performance.started = false;
+        for e in query_balls.iter() {
+            commands.entity(e).despawn();
+        }
+    }
+}
+
+fn remember_velocity(mut ball_query: Query<(&Velocity, &mut VelocityBeforeStep)>) {
+    for (velocity, mut before) in ball_query.iter_mut() {
+        before.0 = velocity.linvel;
+    }
+}
+
+fn detect_note_hits(
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut peg_query: Query<(&NotesList, &PlayMode, &mut SequenceState, &Voice, &InstrumentId, &Transform, Option<&Bar>)>,
+    ball_query: Query<(&Ball, &VelocityBeforeStep, &Transform)>,
+    mut arpeggios: ResMut<Arpeggios>,
+    mut note_event_writer: EventWriter<NoteEvent>,
+) {
+    for collision_event in collision_events.read() {
+        if let CollisionEvent::Started(e1, e2, _flags) = collision_event {
+            let (peg, ball) = if peg_query.contains(*e1) { (*e1, *e2) } else { (*e2, *e1) };
+            let Ok((notes, mode, mut state, voice, instrument, transform, bar)) = peg_query.get_mut(peg) else { continue };
+            let Ok((Ball { spawner }, VelocityBeforeStep(velocity), ball_transform)) = ball_query.get(ball) else { continue };
+            // The velocity after the step has already bounced, and keeps any speed the ball had
+            // sliding along the peg, so measure how fast it was approaching instead.
+            let normal = rapier_context
+                .contact_pair(ball, peg)
+                .and_then(|pair| pair.manifolds().find(|manifold| manifold.num_points() > 0).map(|manifold| manifold.normal()))
+                .unwrap_or_else(|| (ball_transform.translation - transform.translation).truncate().normalize_or_zero());
+            let note_under;
+            let notes = match bar {
+                Some(bar) if bar.split => {
+                    note_under = bar.note_under(&notes.0, transform, ball_transform.translation);
+                    note_under.as_slice()
+                }
+                _ => &notes.0,
+            };
+            let mut notes = state.hit(notes, mode).into_iter();
+            let Some(first) = notes.next() else { continue };
+            let note_event = NoteEvent {
+                peg,
+                notes: first,
+                voice: voice.clone(),
+                instrument: instrument.clone(),
+                speed: velocity.dot(normal).abs(),
+                position: transform.translation.truncate(),
+                spawner: *spawner,
+                hit: true,
+            };
+            if let PlayMode::Arpeggio { rate, .. } = *mode {
+                arpeggios.start(time.elapsed_seconds_f64(), rate, notes.collect(), &note_event);
+            }
+            note_event_writer.send(note_event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ball_from_spawner_plays_peg_below() {
+        let mut objects = BTreeMap::new();
+        objects.insert(0, Object::BallSpawner { x: 0., y: 200., pattern: SpawnPattern::default() });
+        objects.insert(1, Object::Peg {
+            x: 0.,
+            y: 0.,
+            notes: vec![Pitch(60), Pitch(64), Pitch(67)],
+            voice: Voice::default(),
+            instrument: InstrumentId::default(),
+            mode: PlayMode::Chord,
+        });
+        let file = SceneFile {
+            metadata: SceneMetadata::default(),
+            scene: SceneObjects { objects, object_count: 2 },
+        };
+        let mut app = headless_app(&file);
+        app.update();
+        app.world.send_event(StartPerformance);
+        let mut hits = Vec::new();
+        for tick in 0..(2. / TIMESTEP) as u64 {
+            app.update();
+            hits.extend(app.world.resource_mut::<Events<NoteEvent>>().drain().map(|note_event| (tick, note_event)));
+        }
+        let (tick, note_event) = hits.first().expect("the ball never hit the peg");
+        assert!(*tick > 0, "the ball hit the peg as soon as it was dropped");
+        assert_eq!(note_event.notes, [Pitch(60), Pitch(64), Pitch(67)]);
+        assert_eq!(note_event.position, Vec2::ZERO);
+        assert_eq!(note_event.spawner, Some(0));
+        assert!(note_event.hit);
+        assert!(note_event.speed > 0.);
+    }
+}
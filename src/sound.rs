@@ -0,0 +1,165 @@
+use bevy::{audio::Volume, ecs::system::SystemParam, prelude::*};
+use crate::drums::{Drum, DrumHit};
+use crate::dynamics::{Dynamics, Touch};
+use crate::instruments::{InstrumentId, Instruments};
+use crate::scene::SceneMetadata;
+use crate::simulation::{start_performance, NoteEvent, Pitch};
+use crate::spatial::SpatialAudio;
+use crate::synth::{SynthNote, Voice};
+use crate::transport::Transport;
+use crate::voices::{VoiceManager, VoiceSettings};
+
+/// Plays the notes triggered by the simulation.
+pub struct SoundPlugin;
+
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Dynamics>()
+            .add_systems(Startup, setup_sound)
+            .add_event::<PreviewNotes>()
+            .add_systems(FixedUpdate, play_notes)
+            .add_systems(Update, preview_notes)
+            .add_systems(FixedUpdate, play_metronome.after(start_performance))
+            .add_systems(FixedUpdate, cleanup_sounds.before(play_notes));
+    }
+}
+
+fn setup_sound(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn(AudioBundle {
+        source: asset_server.load("sounds/default/c3.ogg"),
+        ..default()
+    });
+}
+
+fn cleanup_sounds(
+    mut commands: Commands,
+    controller: Query<(Entity, &AudioSink)>,
+    spatial_controller: Query<(Entity, &SpatialAudioSink)>,
+    mut voice_manager: ResMut<VoiceManager>,
+) {
+    for (id, sink) in controller.iter() {
+        if sink.empty() {
+            commands.entity(id).despawn();
+            voice_manager.remove(id);
+        }
+    }
+    for (id, sink) in spatial_controller.iter() {
+        if sink.empty() {
+            commands.entity(id).despawn();
+            voice_manager.remove(id);
+        }
+    }
+}
+
+/// Plays notes outside of a performance, e.g. to try out a peg's sound in the inspector.
+#[derive(Event)]
+pub struct PreviewNotes {
+    pub notes: Vec<Pitch>,
+    pub voice: Voice,
+    pub instrument: InstrumentId,
+}
+
+/// Everything needed to start notes, with the voice manager keeping them under the polyphony limit.
+#[derive(SystemParam)]
+struct NotePlayer<'w, 's> {
+    commands: Commands<'w, 's>,
+    asset_server: Res<'w, AssetServer>,
+    synth_notes: ResMut<'w, Assets<SynthNote>>,
+    drum_hits: ResMut<'w, Assets<DrumHit>>,
+    instruments: Res<'w, Instruments>,
+    scene_metadata: Res<'w, SceneMetadata>,
+    voice_settings: Res<'w, VoiceSettings>,
+    voice_manager: ResMut<'w, VoiceManager>,
+}
+
+impl NotePlayer<'_, '_> {
+    fn play(
+        &mut self,
+        notes: &[Pitch],
+        voice: &Voice,
+        instrument: &InstrumentId,
+        touch: Touch,
+        emitter: Option<(&SpatialAudio, Vec2)>,
+    ) {
+        let mut settings = PlaybackSettings { volume: Volume::new(touch.volume * self.voice_manager.gain()), ..default() };
+        let mut transform = TransformBundle::default();
+        if let Some((spatial_audio, position)) = emitter.filter(|(spatial_audio, _)| spatial_audio.enabled) {
+            settings.spatial = true;
+            settings.spatial_scale = Some(spatial_audio.scale());
+            transform = TransformBundle::from_transform(Transform::from_translation(position.extend(0.)));
+        }
+        for &note in notes {
+            let tuning = &self.scene_metadata.tuning;
+            self.voice_manager.make_room(&mut self.commands, self.voice_settings.max_voices);
+            let entity = match voice {
+                Voice::Samples => {
+                    let Some(instrument) = self.instruments.get(instrument) else { continue };
+                    // Steps the tuning leaves unmapped are silent.
+                    let Some(frequency) = tuning.frequency(note) else { continue };
+                    let (path, speed) = instrument.nearest_recording(frequency);
+                    let source = self.asset_server.load(path.to_owned());
+                    self.commands.spawn((AudioBundle { source, settings: PlaybackSettings { speed, ..settings } }, transform))
+                }
+                Voice::Synth(patch) => {
+                    let Some(frequency) = tuning.frequency(note) else { continue };
+                    let source = self.synth_notes.add(SynthNote::struck(patch, frequency, touch));
+                    self.commands.spawn((AudioSourceBundle { source, settings }, transform))
+                }
+                Voice::Drum(Drum::Custom(path)) => {
+                    let source = self.asset_server.load(path.clone());
+                    self.commands.spawn((AudioBundle { source, settings }, transform))
+                }
+                Voice::Drum(drum) => {
+                    let source = self.drum_hits.add(DrumHit(drum.clone()));
+                    self.commands.spawn((AudioSourceBundle { source, settings }, transform))
+                }
+            }
+            .id();
+            self.voice_manager.add(entity, touch.volume);
+        }
+    }
+}
+
+fn play_notes(
+    time: Res<Time>,
+    mut note_events: EventReader<NoteEvent>,
+    mut player: NotePlayer,
+    dynamics: Res<Dynamics>,
+    spatial_audio: Res<SpatialAudio>,
+) {
+    let now = time.elapsed_seconds();
+    for note_event in note_events.read() {
+        let cooldown = player.voice_settings.cooldown;
+        // Later notes of an arpeggio aren't hits, so the cooldown doesn't apply to them.
+        if note_event.hit && !player.voice_manager.retrigger.allow(note_event.peg, now, cooldown) {
+            continue;
+        }
+        player.play(
+            &note_event.notes,
+            &note_event.voice,
+            &note_event.instrument,
+            dynamics.touch(note_event.speed),
+            Some((&spatial_audio, note_event.position)),
+        );
+    }
+}
+
+/// Clicks on every beat while the metronome is on, with a clap on the first beat of each bar.
+fn play_metronome(transport: Res<Transport>, mut preview_writer: EventWriter<PreviewNotes>) {
+    if !transport.metronome {
+        return;
+    }
+    let Some(downbeat) = transport.beat_this_tick() else { return };
+    preview_writer.send(PreviewNotes {
+        notes: vec![Pitch(60)],
+        voice: Voice::Drum(if downbeat { Drum::Clap } else { Drum::HiHat }),
+        instrument: InstrumentId::default(),
+    });
+}
+
+fn preview_notes(mut preview_events: EventReader<PreviewNotes>, mut player: NotePlayer) {
+    for PreviewNotes { notes, voice, instrument } in preview_events.read() {
+        player.play(notes, voice, instrument, Touch::FULL, None);
+    }
+}
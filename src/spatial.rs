@@ -0,0 +1,98 @@
+use bevy::{audio::SpatialScale, ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use crate::camera::MainCamera;
+
+/// Pans notes by where their peg is on the board, with the listener on `MainCamera`.
+pub struct SpatialAudioPlugin;
+
+impl Plugin for SpatialAudioPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SpatialAudio>()
+            .add_systems(Update, update_listener);
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct SpatialAudio {
+    pub enabled: bool,
+    /// The distance between the listener's ears, in pixels. Pegs half this far to either side of
+    /// the camera are panned as far as they go.
+    pub width: f32,
+    /// Notes from pegs further than this from the camera get quieter, if set.
+    pub falloff: Option<f32>,
+}
+
+impl Default for SpatialAudio {
+    fn default() -> Self {
+        SpatialAudio {
+            enabled: true,
+            width: 1280.,
+            falloff: None,
+        }
+    }
+}
+
+impl SpatialAudio {
+    /// Scales pixels into the units rodio works in, where the volume starts falling off at a
+    /// distance of 1. Without a falloff, every peg on the board stays well inside that.
+    pub fn scale(&self) -> SpatialScale {
+        SpatialScale::new_2d(1. / self.falloff.unwrap_or(1e5))
+    }
+
+    /// Left and right gains for a note at `offset` from the listener, worked out the way rodio's
+    /// spatial sinks do, so rendered audio matches what plays in the editor.
+    pub fn gains(&self, offset: Vec2) -> [f32; 2] {
+        if !self.enabled {
+            return [1., 1.];
+        }
+        let scale = self.scale().0.truncate();
+        let ear = Vec2::new(self.width / 2., 0.);
+        let left = ((offset + ear) * scale).length();
+        let right = ((offset - ear) * scale).length();
+        let gap = (2. * ear * scale).length();
+        let gain = |near: f32, far: f32| {
+            let pan = (((far - near) / gap + 1.) / 4. + 0.5).min(1.);
+            pan * (1. / (near * near)).min(1.)
+        };
+        [gain(left, right), gain(right, left)]
+    }
+}
+
+fn update_listener(
+    spatial_audio: Res<SpatialAudio>,
+    camera_query: Query<Entity, With<MainCamera>>,
+    mut commands: Commands,
+) {
+    if !spatial_audio.is_changed() {
+        return;
+    }
+    for camera in camera_query.iter() {
+        commands.entity(camera).insert(SpatialListener::new(spatial_audio.width));
+    }
+}
+
+/// The side panel's stereo settings.
+#[derive(SystemParam)]
+pub struct SpatialAudioUi<'w> {
+    spatial_audio: ResMut<'w, SpatialAudio>,
+}
+
+impl SpatialAudioUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let mut spatial_audio = self.spatial_audio.clone();
+        ui.checkbox(&mut spatial_audio.enabled, "Pan by peg position");
+        ui.add_enabled_ui(spatial_audio.enabled, |ui| {
+            ui.add(egui::Slider::new(&mut spatial_audio.width, 100.0..=5000.).logarithmic(true).text("Width").suffix(" px"));
+            let mut attenuate = spatial_audio.falloff.is_some();
+            ui.checkbox(&mut attenuate, "Quieter with distance");
+            let mut falloff = spatial_audio.falloff.unwrap_or(800.);
+            if attenuate {
+                ui.add(egui::Slider::new(&mut falloff, 100.0..=5000.).logarithmic(true).text("Falloff").suffix(" px"));
+            }
+            spatial_audio.falloff = attenuate.then_some(falloff);
+        });
+        // Only touch the resource when something changed, so the listener isn't rebuilt every frame.
+        self.spatial_audio.set_if_neq(spatial_audio);
+    }
+}
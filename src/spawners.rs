@@ -0,0 +1,251 @@
+use bevy::prelude::*;
+use bevy_egui::egui;
+use serde::{Deserialize, Serialize};
+
+/// When a ball spawner drops balls, counted in beats from Start at the scene's tempo, and how it
+/// throws them.
+#[derive(Component, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SpawnPattern {
+    pub rhythm: Rhythm,
+    /// How far every other step is pushed back, as a fraction of a step. 0 is straight and 1/3
+    /// is a triplet shuffle.
+    pub swing: f32,
+    /// How many balls each onset drops.
+    pub burst: u32,
+    /// The time between the balls of a burst, in beats.
+    pub burst_gap: f32,
+    /// How fast balls are thrown, in pixels per second.
+    pub speed: f32,
+    /// Which way balls are thrown, in degrees from straight down, with positive angles to the
+    /// right.
+    pub direction: f32,
+}
+
+impl Default for SpawnPattern {
+    /// One ball, dropped when the performance starts.
+    fn default() -> Self {
+        SpawnPattern {
+            rhythm: Rhythm::Once,
+            swing: 0.,
+            burst: 1,
+            burst_gap: 0.25,
+            speed: 0.,
+            direction: 0.,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Rhythm {
+    /// A single onset on Start.
+    Once,
+    /// An onset every `beats` beats.
+    Every { beats: f32 },
+    /// `pulses` onsets spread as evenly as they go over a loop of `steps` steps, each `step`
+    /// beats long, with the loop started `rotation` steps in.
+    Euclidean { pulses: u32, steps: u32, step: f32, rotation: u32 },
+}
+
+impl Rhythm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rhythm::Once => "Once",
+            Rhythm::Every { .. } => "Every N beats",
+            Rhythm::Euclidean { .. } => "Euclidean",
+        }
+    }
+
+    /// The length of a step in beats, and which steps of the repeating loop have an onset. `None`
+    /// for rhythms that don't repeat.
+    fn steps(&self) -> Option<(f64, Vec<bool>)> {
+        match *self {
+            Rhythm::Once => None,
+            Rhythm::Every { beats } => Some((beats as f64, vec![true])),
+            Rhythm::Euclidean { pulses, steps, step, rotation } => {
+                let steps = steps.max(1) as u64;
+                let pulses = (pulses as u64).min(steps);
+                let rotation = rotation as u64 % steps;
+                // Bresenham's line spreads the pulses the same way Bjorklund's algorithm does, up to
+                // rotation. Both factors are below `2 * steps`, so the product fits in a u64.
+                let hits = (0..steps).map(|i| ((i + rotation) * pulses) % steps < pulses).collect();
+                Some((step as f64, hits))
+            }
+        }
+    }
+}
+
+/// The shortest step a spawner can have, in beats, so a typo can't flood the board with balls.
+const MIN_STEP: f64 = 1. / 16.;
+
+impl SpawnPattern {
+    /// The beats in `from..to` at which a ball drops, counting from Start.
+    pub fn drops(&self, from: f64, to: f64) -> Vec<f64> {
+        let burst = self.burst.max(1);
+        let gap = self.burst_gap.max(0.) as f64;
+        let burst_length = (burst - 1) as f64 * gap;
+        let onsets: Vec<f64> = match self.rhythm.steps() {
+            None => vec![0.],
+            Some((step, hits)) => {
+                let step = step.max(MIN_STEP);
+                let swing = self.swing.clamp(0., 1.) as f64 * step;
+                // Start early enough to catch bursts and swung steps that began before `from`.
+                let first = ((from - burst_length - swing) / step).floor().max(0.) as u64;
+                let last = (to / step).ceil() as u64;
+                (first..=last)
+                    .filter(|&i| hits[(i % hits.len() as u64) as usize])
+                    .map(|i| i as f64 * step + if i % 2 == 1 { swing } else { 0. })
+                    .collect()
+            }
+        };
+        onsets
+            .into_iter()
+            .flat_map(|onset| (0..burst).map(move |i| onset + i as f64 * gap))
+            .filter(|&beat| (from..to).contains(&beat))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.rhythm {
+            Rhythm::Once => {}
+            Rhythm::Every { beats } if !(beats.is_finite() && beats > 0.) => {
+                return Err("the spawner's rhythm must be a positive number of beats".into());
+            }
+            Rhythm::Euclidean { steps: 0, .. } => return Err("the spawner's rhythm has no steps".into()),
+            Rhythm::Euclidean { step, .. } if !(step.is_finite() && step > 0.) => {
+                return Err("the spawner's steps must be a positive number of beats".into());
+            }
+            Rhythm::Every { .. } | Rhythm::Euclidean { .. } => {}
+        }
+        if ![self.swing, self.burst_gap, self.speed, self.direction].iter().all(|value| value.is_finite()) {
+            return Err("the spawner's swing, burst gap, speed and direction must be numbers".into());
+        }
+        Ok(())
+    }
+
+    /// The velocity balls are thrown at.
+    pub fn velocity(&self) -> Vec2 {
+        let angle = self.direction.to_radians();
+        self.speed * Vec2::new(angle.sin(), -angle.cos())
+    }
+}
+
+/// Edits a spawner's pattern.
+pub fn spawn_pattern_editor(ui: &mut egui::Ui, pattern: &mut SpawnPattern) {
+    egui::ComboBox::from_label("Rhythm")
+        .selected_text(pattern.rhythm.name())
+        .show_ui(ui, |ui| {
+            let candidates = [
+                Rhythm::Once,
+                Rhythm::Every { beats: 1. },
+                Rhythm::Euclidean { pulses: 3, steps: 8, step: 0.5, rotation: 0 },
+            ];
+            for candidate in candidates {
+                if ui.selectable_label(pattern.rhythm.name() == candidate.name(), candidate.name()).clicked()
+                    && pattern.rhythm.name() != candidate.name()
+                {
+                    pattern.rhythm = candidate;
+                }
+            }
+        });
+    match &mut pattern.rhythm {
+        Rhythm::Once => {}
+        Rhythm::Every { beats } => {
+            ui.add(egui::DragValue::new(beats).clamp_range(MIN_STEP..=64.).speed(0.25).prefix("Every ").suffix(" beats"));
+        }
+        Rhythm::Euclidean { pulses, steps, step, rotation } => {
+            // Files are validated, but keep the ranges below well formed regardless.
+            *steps = (*steps).max(1);
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(pulses).clamp_range(1..=*steps).suffix(" hits"));
+                ui.add(egui::DragValue::new(steps).clamp_range(1..=32).prefix("in ").suffix(" steps"));
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(step).clamp_range(MIN_STEP..=4.).speed(0.05).suffix(" beats each"));
+                ui.add(egui::DragValue::new(rotation).clamp_range(0..=steps.saturating_sub(1)).prefix("rotated "));
+            });
+        }
+    }
+    if pattern.rhythm != Rhythm::Once {
+        ui.add(egui::Slider::new(&mut pattern.swing, 0.0..=0.75).text("Swing"));
+    }
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(&mut pattern.burst).clamp_range(1..=16).prefix("Burst of "));
+        if pattern.burst > 1 {
+            ui.add(egui::DragValue::new(&mut pattern.burst_gap).clamp_range(MIN_STEP..=4.).speed(0.05).suffix(" beats apart"));
+        }
+    });
+    ui.add(egui::Slider::new(&mut pattern.speed, 0.0..=2000.).text("Throw speed").suffix(" px/s"));
+    if pattern.speed > 0. {
+        ui.add(egui::Slider::new(&mut pattern.direction, -180.0..=180.).text("Direction").suffix("°"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn euclidean(pulses: u32, steps: u32, rotation: u32) -> Rhythm {
+        Rhythm::Euclidean { pulses, steps, step: 0.5, rotation }
+    }
+
+    fn hits(rhythm: &Rhythm) -> String {
+        rhythm.steps().unwrap().1.into_iter().map(|hit| if hit { 'x' } else { '.' }).collect()
+    }
+
+    fn pattern(rhythm: Rhythm) -> SpawnPattern {
+        SpawnPattern { rhythm, ..SpawnPattern::default() }
+    }
+
+    #[test]
+    fn spreads_pulses_evenly() {
+        assert_eq!(hits(&euclidean(3, 8, 0)), "x..x..x.");
+        // Bjorklund gives x.xx.xx., which this is a rotation of.
+        assert_eq!(hits(&euclidean(5, 8, 0)), "x.x.xx.x");
+        assert_eq!(hits(&euclidean(3, 8, 1)), "..x..x.x");
+        // More pulses than steps fill every step.
+        assert_eq!(hits(&euclidean(9, 4, 0)), "xxxx");
+    }
+
+    #[test]
+    fn rotation_wraps_around_the_loop() {
+        assert_eq!(hits(&euclidean(3, 8, 9)), hits(&euclidean(3, 8, 1)));
+        assert_eq!(hits(&euclidean(3, 8, u32::MAX)), hits(&euclidean(3, 8, 7)));
+    }
+
+    #[test]
+    fn rejects_rhythms_without_steps() {
+        assert!(pattern(euclidean(3, 0, 0)).validate().is_err());
+        assert!(pattern(Rhythm::Every { beats: 0. }).validate().is_err());
+        assert!(pattern(euclidean(3, 8, 0)).validate().is_ok());
+    }
+
+    #[test]
+    fn drops_are_half_open() {
+        // Steps 0, 3 and 6 of half a beat each, looping every 4 beats.
+        let pattern = pattern(euclidean(3, 8, 0));
+        assert_eq!(pattern.drops(0., 4.), [0., 1.5, 3.]);
+        assert_eq!(pattern.drops(0., 3.), [0., 1.5]);
+        assert_eq!(pattern.drops(3., 4.), [3.]);
+        assert_eq!(pattern.drops(2., 5.), [3., 4.]);
+        assert_eq!(pattern.drops(4., 8.), [4., 5.5, 7.]);
+    }
+
+    #[test]
+    fn swing_pushes_back_every_other_step() {
+        let pattern = SpawnPattern { swing: 0.5, ..pattern(Rhythm::Every { beats: 1. }) };
+        assert_eq!(pattern.drops(0., 4.), [0., 1.5, 2., 3.5]);
+        assert!(pattern.drops(1., 1.5).is_empty());
+        assert_eq!(pattern.drops(1.5, 2.), [1.5]);
+    }
+
+    #[test]
+    fn bursts_carry_across_calls() {
+        let once = SpawnPattern { burst: 3, burst_gap: 0.25, ..SpawnPattern::default() };
+        assert_eq!(once.drops(0., 0.3), [0., 0.25]);
+        assert_eq!(once.drops(0.3, 1.), [0.5]);
+        // A burst that began in an earlier call still finishes.
+        let every = SpawnPattern { burst: 2, burst_gap: 0.5, ..pattern(Rhythm::Every { beats: 2. }) };
+        assert_eq!(every.drops(2.2, 3.), [2.5]);
+        assert_eq!(every.drops(0., 4.), [0., 0.5, 2., 2.5]);
+    }
+}
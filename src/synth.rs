@@ -0,0 +1,220 @@
+use bevy::{
+    audio::{AddAudioSource, Decodable, Source},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{PI, TAU};
+use std::time::Duration;
+use crate::drums::Drum;
+use crate::dynamics::Touch;
+
+/// Registers `SynthNote` as an audio source, so synthesized notes play like the ogg samples.
+pub struct SynthPlugin;
+
+impl Plugin for SynthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<SynthNote>();
+    }
+}
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// How a peg sounds.
+#[derive(Component, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum Voice {
+    /// Recordings from the peg's instrument.
+    #[default]
+    Samples,
+    Synth(SynthPatch),
+    /// Percussion pegs play their drum rather than the note.
+    Drum(Drum),
+}
+
+impl Voice {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Voice::Samples => "Samples",
+            Voice::Synth(_) => "Synth",
+            Voice::Drum(_) => "Drum",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 4] = [Waveform::Sine, Waveform::Triangle, Waveform::Saw, Waveform::Square];
+
+    /// The waveform's value at `phase`, which runs from 0 to 1 over one cycle.
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1. - 4. * (phase - 0.5).abs(),
+            Waveform::Saw => 2. * phase - 1.,
+            Waveform::Square => if phase < 0.5 { 1. } else { -1. },
+        }
+    }
+}
+
+/// Settings for the built-in synthesizer. Times are in seconds.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SynthPatch {
+    pub waveform: Waveform,
+    pub attack: f32,
+    pub decay: f32,
+    /// Level held after the decay, from 0 to 1.
+    pub sustain: f32,
+    pub release: f32,
+    /// How long the note is held before it starts to release. A hit has no duration of its own.
+    pub length: f32,
+    /// Low-pass filter cutoff in Hz.
+    pub cutoff: f32,
+    /// Filter resonance, from 0 (none) to 1 (close to self-oscillation).
+    pub resonance: f32,
+}
+
+impl Default for SynthPatch {
+    fn default() -> Self {
+        SynthPatch {
+            waveform: Waveform::Saw,
+            attack: 0.01,
+            decay: 0.2,
+            sustain: 0.5,
+            release: 0.4,
+            length: 0.25,
+            cutoff: 2000.,
+            resonance: 0.2,
+        }
+    }
+}
+
+impl SynthPatch {
+    /// The envelope level while the note is held.
+    fn held_level(&self, time: f32) -> f32 {
+        if time < self.attack {
+            time / self.attack
+        } else if time < self.attack + self.decay {
+            1. - (1. - self.sustain) * (time - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+
+    fn envelope(&self, time: f32) -> f32 {
+        if time < self.length {
+            self.held_level(time)
+        } else {
+            // Release from wherever the envelope was when the note was let go.
+            self.held_level(self.length) * (1. - (time - self.length) / self.release).max(0.)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let times = [self.attack, self.decay, self.release, self.length];
+        if !times.iter().all(|time| time.is_finite() && *time >= 0.) {
+            return Err("the synth's envelope times must be zero or more seconds".into());
+        }
+        if ![self.sustain, self.cutoff, self.resonance].iter().all(|value| value.is_finite()) || self.cutoff <= 0. {
+            return Err("the synth's sustain, cutoff and resonance must be numbers, with a positive cutoff".into());
+        }
+        Ok(())
+    }
+
+    /// How long a note sounds, in seconds, counting anything out of range as no time at all.
+    fn duration(&self) -> f32 {
+        let duration = self.length.max(0.) + self.release.max(0.);
+        if duration.is_finite() { duration } else { 0. }
+    }
+}
+
+/// One synthesized note, played with an `AudioSourceBundle<SynthNote>`.
+#[derive(Asset, TypePath, Clone)]
+pub struct SynthNote {
+    pub patch: SynthPatch,
+    pub frequency: f32,
+}
+
+impl SynthNote {
+    /// A note at `frequency` played with `touch`, which darkens the filter for soft hits.
+    pub fn struck(patch: &SynthPatch, frequency: f32, touch: Touch) -> Self {
+        let mut patch = patch.clone();
+        patch.cutoff *= touch.brightness;
+        SynthNote { patch, frequency }
+    }
+}
+
+/// Peak level of a note before the envelope, leaving headroom for chords.
+const AMPLITUDE: f32 = 0.3;
+
+impl Decodable for SynthNote {
+    type DecoderItem = f32;
+    type Decoder = SynthDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        let cutoff = self.patch.cutoff.clamp(20., SAMPLE_RATE as f32 / 6.);
+        SynthDecoder {
+            note: self.clone(),
+            frame: 0,
+            frames: (self.patch.duration() * SAMPLE_RATE as f32) as u64,
+            phase: 0.,
+            filter_frequency: 2. * (PI * cutoff / SAMPLE_RATE as f32).sin(),
+            filter_damping: 2. * (1. - self.patch.resonance.clamp(0., 0.95)),
+            low: 0.,
+            band: 0.,
+        }
+    }
+}
+
+/// Renders a `SynthNote` one mono sample at a time, through a state-variable low-pass filter.
+pub struct SynthDecoder {
+    note: SynthNote,
+    frame: u64,
+    frames: u64,
+    phase: f32,
+    filter_frequency: f32,
+    filter_damping: f32,
+    low: f32,
+    band: f32,
+}
+
+impl Iterator for SynthDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame >= self.frames {
+            return None;
+        }
+        let time = self.frame as f32 / SAMPLE_RATE as f32;
+        let raw = self.note.patch.waveform.sample(self.phase);
+        self.phase = (self.phase + self.note.frequency / SAMPLE_RATE as f32).fract();
+        self.low += self.filter_frequency * self.band;
+        let high = raw - self.low - self.filter_damping * self.band;
+        self.band += self.filter_frequency * high;
+        self.frame += 1;
+        Some(self.low * self.note.patch.envelope(time) * AMPLITUDE)
+    }
+}
+
+impl Source for SynthDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f32(self.note.patch.duration()))
+    }
+}
@@ -0,0 +1,190 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use serde::{Deserialize, Serialize};
+use crate::scene::SceneMetadata;
+use crate::simulation::{Performance, ResetPerformance, StartPerformance, TIMESTEP};
+
+/// Keeps the beat: a clock that counts beats and bars at the scene's tempo and time signature,
+/// which the spawners and the metronome follow. Added by `SimulationPlugin`, so headless runs
+/// keep time the same way.
+pub struct TransportPlugin;
+
+impl Plugin for TransportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Transport>()
+            .add_systems(FixedUpdate, (sync_transport, advance_transport).chain());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TimeSignature {
+    /// Beats in a bar.
+    pub beats: u32,
+    /// The note value of a beat: 4 for quarter notes, 8 for eighths and so on.
+    pub unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature { beats: 4, unit: 4 }
+    }
+}
+
+impl TimeSignature {
+    pub const UNITS: [u32; 4] = [2, 4, 8, 16];
+}
+
+#[derive(Resource)]
+pub struct Transport {
+    /// Copied from `SceneMetadata`, which is where the scene keeps them.
+    pub bpm: f32,
+    pub time_signature: TimeSignature,
+    /// Whether to click on every beat.
+    pub metronome: bool,
+    /// Whether Start waits for the next bar instead of starting straight away.
+    pub quantize_start: bool,
+    /// Whether Start has been pressed and is waiting for the next bar.
+    pub start_queued: bool,
+    /// Beats counted at the start and end of the current tick. Start resets the count, so during
+    /// a performance these are beats since Start.
+    pub previous: f64,
+    pub position: f64,
+    /// The beat a queued Start's bar began on, until the performance starts from it.
+    bar_start: Option<f64>,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            bpm: 120.,
+            time_signature: TimeSignature::default(),
+            metronome: false,
+            quantize_start: false,
+            start_queued: false,
+            previous: 0.,
+            position: 0.,
+            bar_start: None,
+        }
+    }
+}
+
+impl Transport {
+    /// How far one physics step moves the transport, in beats.
+    fn beats_per_tick(&self) -> f64 {
+        (TIMESTEP * self.bpm / 60.) as f64
+    }
+
+    /// The first multiple of `every` beats the current tick covers, if any.
+    fn crossed(&self, every: f64) -> Option<f64> {
+        let boundary = (self.previous / every).ceil() * every;
+        (boundary < self.position).then_some(boundary)
+    }
+
+    /// The beat that falls in the current tick, if any, and whether it starts a bar.
+    pub fn beat_this_tick(&self) -> Option<bool> {
+        let beat = self.crossed(1.)?;
+        Some(beat.rem_euclid(self.time_signature.beats.max(1) as f64) == 0.)
+    }
+
+    /// Starts counting from 0 at the bar a queued Start was waiting for, or at the start of the
+    /// current tick.
+    pub fn start(&mut self) {
+        let origin = self.bar_start.take().unwrap_or(self.previous);
+        self.previous -= origin;
+        self.position -= origin;
+        self.start_queued = false;
+    }
+
+    /// The current bar and beat, counting from 1.
+    pub fn bar_and_beat(&self) -> (i64, i64) {
+        let beats = self.time_signature.beats.max(1) as i64;
+        let beat = self.position.floor() as i64;
+        (beat.div_euclid(beats) + 1, beat.rem_euclid(beats) + 1)
+    }
+}
+
+fn sync_transport(scene_metadata: Res<SceneMetadata>, mut transport: ResMut<Transport>) {
+    if scene_metadata.is_changed() {
+        transport.bpm = scene_metadata.tempo;
+        transport.time_signature = scene_metadata.time_signature;
+    }
+}
+
+/// Moves the transport on by one tick, starting the performance if a queued Start's bar has come.
+pub fn advance_transport(
+    mut transport: ResMut<Transport>,
+    mut reset_events: EventReader<ResetPerformance>,
+    mut start_event_writer: EventWriter<StartPerformance>,
+) {
+    if reset_events.read().count() > 0 {
+        transport.start_queued = false;
+    }
+    transport.previous = transport.position;
+    transport.position += transport.beats_per_tick();
+    if !transport.start_queued {
+        return;
+    }
+    let bar = transport.time_signature.beats.max(1) as f64;
+    if let Some(bar_start) = transport.crossed(bar) {
+        transport.bar_start = Some(bar_start);
+        start_event_writer.send(StartPerformance);
+    }
+}
+
+/// The transport controls at the top of the side panel.
+#[derive(SystemParam)]
+pub struct TransportUi<'w> {
+    time: ResMut<'w, Time<Virtual>>,
+    transport: ResMut<'w, Transport>,
+    performance: Res<'w, Performance>,
+    start_event_writer: EventWriter<'w, StartPerformance>,
+    reset_event_writer: EventWriter<'w, ResetPerformance>,
+}
+
+impl TransportUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui, scene_metadata: &mut SceneMetadata) {
+        ui.horizontal(|ui| {
+            if ui.button("Pause").clicked() {
+                self.time.pause();
+            }
+            if ui.button("Play").clicked() {
+                self.time.unpause();
+            }
+            if self.performance.started {
+                if ui.button("Reset").clicked() {
+                    self.reset_event_writer.send(ResetPerformance);
+                }
+            } else if self.transport.start_queued {
+                if ui.button("Cancel").on_hover_text("Waiting for the next bar").clicked() {
+                    self.transport.start_queued = false;
+                }
+            } else if ui.button("Start").clicked() {
+                if self.transport.quantize_start {
+                    self.transport.start_queued = true;
+                } else {
+                    self.start_event_writer.send(StartPerformance);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut scene_metadata.tempo).clamp_range(20.0..=300.0).suffix(" bpm"));
+            let time_signature = &mut scene_metadata.time_signature;
+            ui.add(egui::DragValue::new(&mut time_signature.beats).clamp_range(1..=16));
+            egui::ComboBox::from_id_source("time signature unit")
+                .width(40.)
+                .selected_text(format!("/ {}", time_signature.unit))
+                .show_ui(ui, |ui| {
+                    for unit in TimeSignature::UNITS {
+                        ui.selectable_value(&mut time_signature.unit, unit, format!("/ {}", unit));
+                    }
+                });
+            let (bar, beat) = self.transport.bar_and_beat();
+            ui.monospace(format!("{:>3}.{}", bar, beat));
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.transport.metronome, "Metronome");
+            ui.checkbox(&mut self.transport.quantize_start, "Start on the bar");
+        });
+    }
+}
@@ -0,0 +1,378 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use bevy_file_dialog::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::scene::SceneMetadata;
+use crate::simulation::Pitch;
+
+/// Loads Scala scales and keyboard mappings into the scene's tuning.
+pub struct TuningPlugin;
+
+impl Plugin for TuningPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(TuningEditor { divisions: 19, error: None })
+            .add_systems(Update, load_tuning_files);
+    }
+}
+
+/// Marks file dialogs opened for a Scala scale (`.scl`).
+pub struct ScalaFileContents;
+
+/// Marks file dialogs opened for a Scala keyboard mapping (`.kbm`).
+pub struct KeymapFileContents;
+
+/// How the steps pegs store are turned into frequencies. This follows Scala's model: a scale of
+/// intervals that repeats every period, and a keyboard mapping from steps to scale degrees.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Tuning {
+    pub name: String,
+    /// Cents above degree 0 of degrees 1 to n. The last one is the period the scale repeats at,
+    /// which is usually the octave (1200 cents).
+    pub cents: Vec<f64>,
+    pub keymap: Keymap,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Keymap {
+    /// The step that plays degree 0 of the scale.
+    pub middle: u8,
+    /// The step tuned to exactly `reference_frequency`.
+    pub reference: u8,
+    pub reference_frequency: f64,
+    /// The degree each step in a repeating pattern plays, starting from `middle`, or `None` for
+    /// steps that are left silent. If empty, every step plays the next degree.
+    pub mapping: Vec<Option<u32>>,
+    /// How many degrees the pattern moves up each time it repeats.
+    pub octave_degree: u32,
+}
+
+impl Default for Keymap {
+    /// Middle C is degree 0 and A above it is 440 Hz, as on a MIDI keyboard.
+    fn default() -> Self {
+        Keymap {
+            middle: 60,
+            reference: 69,
+            reference_frequency: 440.,
+            mapping: Vec::new(),
+            octave_degree: 0,
+        }
+    }
+}
+
+impl Default for Tuning {
+    /// 12-tone equal temperament, where steps are MIDI note numbers.
+    fn default() -> Self {
+        Tuning::equal(12)
+    }
+}
+
+/// The most cents a scale's period can span: ten octaves.
+const MAX_PERIOD: f64 = 12000.;
+
+/// The frequencies, in Hz, steps can sound at. Steps an extreme tuning puts outside this are
+/// silent, rather than asking the samples to play back at an absurd speed.
+const FREQUENCIES: std::ops::RangeInclusive<f32> = 1.0..=40_000.;
+
+impl Tuning {
+    /// Divides the octave into `divisions` equal steps. Tunings other than 12-TET keep middle C
+    /// where it is, since A is no longer nine steps above it.
+    pub fn equal(divisions: u32) -> Self {
+        let divisions = divisions.max(1);
+        let mut keymap = Keymap::default();
+        if divisions != 12 {
+            keymap.reference = keymap.middle;
+            keymap.reference_frequency = Pitch(60).frequency() as f64;
+        }
+        Tuning {
+            name: if divisions == 12 { "12-tone equal temperament".into() } else { format!("{}-EDO", divisions) },
+            cents: (1..=divisions).map(|step| step as f64 * 1200. / divisions as f64).collect(),
+            keymap,
+        }
+    }
+
+    /// Five-limit just intonation on C, with A at 440 Hz.
+    pub fn just() -> Self {
+        let ratios: [(f64, f64); 12] = [
+            (16., 15.),
+            (9., 8.),
+            (6., 5.),
+            (5., 4.),
+            (4., 3.),
+            (45., 32.),
+            (3., 2.),
+            (8., 5.),
+            (5., 3.),
+            (9., 5.),
+            (15., 8.),
+            (2., 1.),
+        ];
+        Tuning {
+            name: "5-limit just intonation".into(),
+            cents: ratios.iter().map(|&(numerator, denominator)| ratio_to_cents(numerator / denominator)).collect(),
+            keymap: Keymap::default(),
+        }
+    }
+
+    /// Reads a Scala scale file, keeping the current keyboard mapping.
+    pub fn from_scala(text: &str, keymap: Keymap) -> Result<Self, String> {
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+        let name = lines.next().ok_or("the file is empty")?.trim().to_owned();
+        let count: usize = first_word(lines.next().ok_or("the number of notes is missing")?)
+            .parse()
+            .map_err(|_| "the number of notes is not a number")?;
+        let cents = lines
+            .take(count)
+            .map(|line| parse_interval(first_word(line)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        if cents.len() != count {
+            return Err(format!("{} notes are listed, but there are only {}", count, cents.len()));
+        }
+        let tuning = Tuning { name, cents, keymap };
+        tuning.validate()?;
+        Ok(tuning)
+    }
+
+    /// Reads a Scala keyboard mapping file into this tuning.
+    pub fn with_keymap(mut self, text: &str) -> Result<Self, String> {
+        let mut values = text.lines().filter(|line| !line.starts_with('!')).map(first_word);
+        let mut next = |what: &str| values.next().ok_or_else(|| format!("the {} is missing", what));
+        let number = |text: &str, what: &str| text.parse::<u32>().map_err(|_| format!("the {} is not a number", what));
+        let step = |text: &str, what: &str| {
+            text.parse::<u8>().ok().filter(|&step| step <= Pitch::MAX.0).ok_or_else(|| format!("the {} is not a MIDI note", what))
+        };
+        let size = number(next("map size")?, "map size")? as usize;
+        // Only the MIDI range is ever mapped, so anything bigger is a typo.
+        if size > Pitch::MAX.0 as usize + 1 {
+            return Err(format!("the map size is {}, but there are only {} MIDI notes", size, Pitch::MAX.0 as usize + 1));
+        }
+        // The range of keys to map; every step is mapped here, so this is skipped.
+        next("first note")?;
+        next("last note")?;
+        let middle = step(next("middle note")?, "middle note")?;
+        let reference = step(next("reference note")?, "reference note")?;
+        let reference_frequency: f64 = next("reference frequency")?
+            .parse()
+            .ok()
+            .filter(|&frequency: &f64| frequency > 0.)
+            .ok_or("the reference frequency is not a positive number")?;
+        let octave_degree = number(next("formal octave degree")?, "formal octave degree")?;
+        // Mapping entries left off the end of the file are silent.
+        let mut mapping = Vec::with_capacity(size);
+        for _ in 0..size {
+            mapping.push(match values.next() {
+                Some("x") | None => None,
+                Some(degree) => Some(number(degree, "mapping entry")?),
+            });
+        }
+        self.keymap = Keymap { middle, reference, reference_frequency, mapping, octave_degree };
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cents.is_empty() {
+            return Err("the tuning has no notes".into());
+        }
+        if !self.cents.iter().all(|cents| cents.is_finite()) || self.period() <= 0. {
+            return Err("the tuning's period must be above its first note".into());
+        }
+        if self.period() > MAX_PERIOD {
+            return Err("the tuning's period must be ten octaves or less".into());
+        }
+        let reference_frequency = self.keymap.reference_frequency;
+        if !reference_frequency.is_finite() || reference_frequency <= 0. {
+            return Err("the tuning's reference frequency must be positive".into());
+        }
+        Ok(())
+    }
+
+    fn period(&self) -> f64 {
+        self.cents.last().copied().unwrap_or(1200.)
+    }
+
+    /// The scale degree `step` plays, counting from degree 0 at `keymap.middle`, or `None` if the
+    /// keyboard mapping leaves it silent.
+    fn degree(&self, step: Pitch) -> Option<i64> {
+        let offset = step.0 as i64 - self.keymap.middle as i64;
+        let size = self.keymap.mapping.len() as i64;
+        if size == 0 {
+            return Some(offset);
+        }
+        let degree = self.keymap.mapping[offset.rem_euclid(size) as usize]?;
+        Some(degree as i64 + offset.div_euclid(size) * self.keymap.octave_degree as i64)
+    }
+
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let length = self.cents.len() as i64;
+        let within = degree.rem_euclid(length);
+        let below = if within == 0 { 0. } else { self.cents[within as usize - 1] };
+        degree.div_euclid(length) as f64 * self.period() + below
+    }
+
+    /// The frequency `step` sounds at, in Hz, or `None` if it is silent.
+    pub fn frequency(&self, step: Pitch) -> Option<f32> {
+        let reference_cents = self.degree(Pitch(self.keymap.reference)).map_or(0., |degree| self.degree_cents(degree));
+        let cents = self.degree_cents(self.degree(step)?);
+        let frequency = (self.keymap.reference_frequency * cents_to_ratio(cents - reference_cents)) as f32;
+        FREQUENCIES.contains(&frequency).then_some(frequency)
+    }
+}
+
+fn ratio_to_cents(ratio: f64) -> f64 {
+    1200. * ratio.log2()
+}
+
+fn cents_to_ratio(cents: f64) -> f64 {
+    2f64.powf(cents / 1200.)
+}
+
+/// Scala allows anything after the value on a line, so only the first word counts.
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// A Scala interval: cents if it has a decimal point, otherwise a ratio such as `3/2` or `2`.
+fn parse_interval(text: &str) -> Result<f64, String> {
+    let error = || format!("`{}` is not an interval", text);
+    if text.contains('.') {
+        return text.parse().map_err(|_| error());
+    }
+    let (numerator, denominator) = text.split_once('/').unwrap_or((text, "1"));
+    let numerator: f64 = numerator.parse().map_err(|_| error())?;
+    let denominator: f64 = denominator.parse().map_err(|_| error())?;
+    if numerator <= 0. || denominator <= 0. {
+        return Err(error());
+    }
+    Ok(ratio_to_cents(numerator / denominator))
+}
+
+#[derive(Resource)]
+pub struct TuningEditor {
+    /// The number of steps for the next equal tuning.
+    divisions: u32,
+    error: Option<String>,
+}
+
+fn load_tuning_files(
+    mut scala_events: EventReader<DialogFileLoaded<ScalaFileContents>>,
+    mut keymap_events: EventReader<DialogFileLoaded<KeymapFileContents>>,
+    mut scene_metadata: ResMut<SceneMetadata>,
+    mut editor: ResMut<TuningEditor>,
+) {
+    for loaded in scala_events.read() {
+        let result = std::str::from_utf8(&loaded.contents)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Tuning::from_scala(text, scene_metadata.tuning.keymap.clone()));
+        match result {
+            Ok(tuning) => {
+                scene_metadata.tuning = tuning;
+                editor.error = None;
+            }
+            Err(e) => editor.error = Some(format!("could not load {}: {}", loaded.file_name, e)),
+        }
+    }
+    for loaded in keymap_events.read() {
+        let result = std::str::from_utf8(&loaded.contents)
+            .map_err(|e| e.to_string())
+            .and_then(|text| scene_metadata.tuning.clone().with_keymap(text));
+        match result {
+            Ok(tuning) => {
+                scene_metadata.tuning = tuning;
+                editor.error = None;
+            }
+            Err(e) => editor.error = Some(format!("could not load {}: {}", loaded.file_name, e)),
+        }
+    }
+}
+
+/// The side panel's tuning settings, shown in the Scene section.
+#[derive(SystemParam)]
+pub struct TuningUi<'w> {
+    editor: ResMut<'w, TuningEditor>,
+}
+
+impl TuningUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui, tuning: &mut Tuning, commands: &mut Commands) {
+        ui.label(format!("Tuning: {}", tuning.name));
+        ui.horizontal(|ui| {
+            if ui.button("12-TET").clicked() {
+                *tuning = Tuning::default();
+            }
+            if ui.button("Just").clicked() {
+                *tuning = Tuning::just();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.editor.divisions).clamp_range(1..=96).suffix(" steps"));
+            if ui.button("Equal divisions").clicked() {
+                *tuning = Tuning::equal(self.editor.divisions);
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Load .scl").clicked() {
+                commands.dialog().add_filter("Scala scale", &["scl"]).load_file::<ScalaFileContents>();
+            }
+            if ui.button("Load .kbm").clicked() {
+                commands.dialog().add_filter("Scala keyboard mapping", &["kbm"]).load_file::<KeymapFileContents>();
+            }
+        });
+        if let Some(error) = &self.editor.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("the step is silent");
+        assert!((actual - expected).abs() < expected * 1e-4, "{} Hz is not {} Hz", actual, expected);
+    }
+
+    #[test]
+    fn twelve_tone_equal_temperament_matches_midi() {
+        let tuning = Tuning::default();
+        for note in 0..=Pitch::MAX.0 {
+            assert_close(tuning.frequency(Pitch(note)), Pitch(note).frequency());
+        }
+    }
+
+    #[test]
+    fn reads_scala_scales() {
+        let tuning = Tuning::from_scala("! meantone.scl\n!\nTest scale\n 3\n100.0 cents\n3/2\n2\n", Keymap::default()).unwrap();
+        assert_eq!(tuning.name, "Test scale");
+        assert_eq!(tuning.cents.len(), 3);
+        assert!((tuning.cents[1] - 701.955).abs() < 1e-3);
+        assert_eq!(tuning.cents[2], 1200.);
+        // A is still the reference, and three steps go up an octave.
+        assert_close(tuning.frequency(Pitch(69)), 440.);
+        assert_close(tuning.frequency(Pitch(72)), 880.);
+        assert_close(tuning.frequency(Pitch(70)), 440. * 2f32.powf(100. / 1200.));
+        assert!(Tuning::from_scala("Too short\n3\n100.0\n2\n", Keymap::default()).is_err());
+        assert!(Tuning::from_scala("Bad ratio\n1\n0/2\n", Keymap::default()).is_err());
+        assert!(Tuning::from_scala("Huge period\n1\n1000000.0\n", Keymap::default()).is_err());
+    }
+
+    #[test]
+    fn reads_keyboard_mappings() {
+        let keymap = "! white keys.kbm\n12\n0\n127\n60\n69\n432.0\n12\n0\nx\n2\nx\n4\n5\nx\n7\nx\n9\nx\n11\n";
+        let tuning = Tuning::default().with_keymap(keymap).unwrap();
+        assert_close(tuning.frequency(Pitch(69)), 432.);
+        assert_close(tuning.frequency(Pitch(81)), 864.);
+        assert_eq!(tuning.frequency(Pitch(61)), None);
+        assert_eq!(tuning.frequency(Pitch(70)), None);
+        assert!(Tuning::default().with_keymap("4000000000\n0\n127\n60\n69\n440.0\n12\n").is_err());
+    }
+
+    #[test]
+    fn steps_out_of_hearing_are_silent() {
+        // Every step of 1-EDO is an octave, so most of the MIDI range is far out of hearing.
+        let tuning = Tuning::equal(1);
+        assert_close(tuning.frequency(Pitch(60)), Pitch(60).frequency());
+        assert_eq!(tuning.frequency(Pitch(0)), None);
+        assert_eq!(tuning.frequency(Pitch(127)), None);
+    }
+}
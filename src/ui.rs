@@ -1,7 +1,20 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_file_dialog::prelude::*;
 use bevy_egui::{egui, EguiContexts};
-use crate::pegs::{delete_all_objects, Ball, BallSpawner, DeleteObjects, Object, SceneObjects, SpawnObject};
+use crate::dynamics::DynamicsUi;
+use crate::spatial::SpatialAudioUi;
+use crate::voices::VoicesUi;
+use crate::history::{History, HistoryUi};
+use crate::inspector::{Inspector, InspectorDraft};
+use crate::pegs::NewPegUi;
+use crate::midi::{self, Recording};
+use crate::midi_input::MidiInputUi;
+use crate::midi_output::MidiOutputUi;
+use crate::simulation::{delete_all_objects, Ball, BallSpawner, Peg, Performance, SceneObjects, SpawnObject};
+use crate::scene::{self, SceneCodec, SceneFile, SceneMetadata};
+use crate::transport::{Transport, TransportUi};
+use crate::walls::Wall;
+use crate::tuning::TuningUi;
 use crate::TextFileContents;
 
 pub struct UiPlugin;
@@ -9,74 +22,199 @@ pub struct UiPlugin;
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app
-            .insert_resource(UiState { started: false })
+            .insert_resource(UiState {
+                load_error: None,
+                save_codec: SceneCodec::MessagePack,
+                pending_save: None,
+            })
+            .init_resource::<InspectorDraft>()
             .insert_resource(Time::<Virtual>::default())
+            .add_event::<ReplaceScene>()
             .add_systems(Update, ui)
-            .add_systems(FixedUpdate, load_save_file.after(delete_all_objects));
+            .add_systems(Update, match_saved_codec)
+            .add_systems(FixedUpdate, load_save_file.after(delete_all_objects))
+            .add_systems(FixedUpdate, replace_scene.after(load_save_file));
     }
 }
 
+/// Marks file dialogs opened to save the scene.
+pub struct SceneFileContents;
+
 #[derive(Resource)]
 pub struct UiState {
-    started: bool,
+    load_error: Option<String>,
+    save_codec: SceneCodec,
+    /// The scene being saved and the codec it was encoded with, until the save dialog closes.
+    pending_save: Option<(SceneFile, SceneCodec)>,
+}
+
+/// The side panel's MIDI sections, grouped to keep `ui` under Bevy's system parameter limit.
+#[derive(SystemParam)]
+pub struct MidiUi<'w> {
+    output: MidiOutputUi<'w>,
+    input: MidiInputUi<'w>,
+}
+
+/// The side panel's playback sections.
+#[derive(SystemParam)]
+pub struct SoundUi<'w> {
+    dynamics: DynamicsUi<'w>,
+    spatial_audio: SpatialAudioUi<'w>,
+    voices: VoicesUi<'w>,
 }
 
 pub fn ui(
     mut contexts: EguiContexts,
-    mut time: ResMut<Time<Virtual>>,
+    mut transport: TransportUi,
     mut ui_state: ResMut<UiState>,
-    scene_objects: ResMut<SceneObjects>,
-    query_balls: Query<Entity, With<Ball>>,
-    query_ball_spawners: Query<&Transform, With<BallSpawner>>,
-    mut spawn_event_writer: EventWriter<SpawnObject>,
+    scene_objects: Res<SceneObjects>,
+    mut scene_metadata: ResMut<SceneMetadata>,
+    mut history: HistoryUi,
+    recording: Res<Recording>,
+    mut new_pegs: NewPegUi,
+    mut inspector: Inspector,
+    mut midi: MidiUi,
+    mut sound: SoundUi,
+    mut tuning: TuningUi,
     mut commands: Commands,
-    mut delete_event_writer: EventWriter<DeleteObjects>,
 ) {
+    if let Some(error) = ui_state.load_error.clone() {
+        egui::Window::new("Could not load scene")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(contexts.ctx_mut(), |ui| {
+                ui.label(error);
+                ui.label("The current scene was left unchanged.");
+                if ui.button("OK").clicked() {
+                    ui_state.load_error = None;
+                }
+            });
+    }
+
     egui::SidePanel::left("").show(contexts.ctx_mut(), |ui| {
         ui.label("Settings");
-        if ui.button("Pause").clicked() {
-            time.pause();
-        }
-        if ui.button("Play").clicked() {
-            time.unpause();
-        }
-        if ui_state.started {
-            if ui.button("Reset").clicked() {
-                ui_state.started = false;
-                for e in query_balls.iter() {
-                    commands.entity(e).despawn();
+        transport.show(ui, &mut scene_metadata);
+        ui.collapsing("History", |ui| history.show(ui));
+        ui.collapsing("New pegs", |ui| new_pegs.show(ui));
+        inspector.show(ui);
+        ui.collapsing("Dynamics", |ui| sound.dynamics.show(ui));
+        ui.collapsing("Stereo", |ui| sound.spatial_audio.show(ui));
+        ui.collapsing("Voices", |ui| sound.voices.show(ui));
+        ui.collapsing("MIDI output", |ui| midi.output.show(ui));
+        ui.collapsing("MIDI input", |ui| midi.input.show(ui));
+        ui.collapsing("Scene", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Title");
+                ui.text_edit_singleline(&mut scene_metadata.title);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Author");
+                ui.text_edit_singleline(&mut scene_metadata.author);
+            });
+            ui.separator();
+            tuning.show(ui, &mut scene_metadata.tuning, &mut commands);
+        });
+        egui::ComboBox::from_label("Save format")
+            .selected_text(ui_state.save_codec.name())
+            .show_ui(ui, |ui| {
+                for codec in SceneCodec::ALL {
+                    ui.selectable_value(&mut ui_state.save_codec, codec, codec.name());
                 }
-            }
-        } else {
-            if ui.button("Start").clicked() {
-                ui_state.started = true;
-                for transform in query_ball_spawners.iter() {
-                    spawn_event_writer.send(SpawnObject(Object::Ball(transform.translation.x, transform.translation.y), None));
-                }
-            }
-        }
+            });
         if ui.button("Save").clicked() {
+            let now = scene::now();
+            if scene_metadata.created == 0 {
+                scene_metadata.created = now;
+            }
+            scene_metadata.modified = now;
+            let file = SceneFile {
+                metadata: scene_metadata.clone(),
+                scene: scene_objects.clone(),
+            };
+            let codec = ui_state.save_codec;
+            let bytes = scene::encode(&file, codec);
+            ui_state.pending_save = Some((file, codec));
             commands
                 .dialog()
-                .save_file::<TextFileContents>(rmp_serde::to_vec(&scene_objects.clone()).unwrap());
+                .add_filter(codec.name(), &[codec.extension()])
+                .set_file_name(format!("untitled.{}", codec.extension()))
+                .save_file::<SceneFileContents>(bytes);
         }
         if ui.button("Load").clicked() {
             commands
                 .dialog()
                 .load_file::<TextFileContents>();
-            delete_event_writer.send(DeleteObjects);
+        }
+        let export = ui.add_enabled(!recording.notes.is_empty(), egui::Button::new("Export MIDI"));
+        if export.on_hover_text("Save the notes played since Start was last pressed").clicked() {
+            commands
+                .dialog()
+                .add_filter("MIDI", &["mid"])
+                .set_file_name("untitled.mid")
+                .save_file::<TextFileContents>(midi::write_smf(&recording.notes, scene_metadata.tempo, scene_metadata.time_signature, &scene_metadata.title));
         }
     });
 }
 
+/// The dialog needs the scene's bytes before the file name is chosen, so they are in the codec
+/// picked in the side panel. If the name typed in the dialog calls for a different codec, the
+/// file is written again in that one, so Load can read it back.
+fn match_saved_codec(mut ev_saved: EventReader<DialogFileSaved<SceneFileContents>>, mut ui_state: ResMut<UiState>) {
+    for ev in ev_saved.read() {
+        let Some((file, encoded_with)) = ui_state.pending_save.take() else { continue };
+        let codec = SceneCodec::from_file_name(&ev.file_name);
+        if ev.result.is_err() || codec == encoded_with {
+            continue;
+        }
+        if let Err(e) = std::fs::write(&ev.path, scene::encode(&file, codec)) {
+            warn!("could not save {}: {}", ev.path.display(), e);
+        }
+    }
+}
 
 fn load_save_file(
     mut ev_loaded: EventReader<DialogFileLoaded<TextFileContents>>,
+    mut ui_state: ResMut<UiState>,
+    mut replace_scene_writer: EventWriter<ReplaceScene>,
+) {
+    for ev in ev_loaded.read() {
+        // Only touch the current scene once the new one is known to be good.
+        match scene::decode(&ev.contents, SceneCodec::from_file_name(&ev.file_name)) {
+            Ok(file) => {
+                replace_scene_writer.send(ReplaceScene(file));
+            }
+            Err(e) => {
+                ui_state.load_error = Some(format!("{}: {}", ev.file_name, e));
+            }
+        }
+    }
+}
+
+/// Throws away the current scene, including its undo history, and spawns a new one.
+#[derive(Event)]
+pub struct ReplaceScene(pub SceneFile);
+
+fn replace_scene(
+    mut replace_scene_events: EventReader<ReplaceScene>,
+    mut performance: ResMut<Performance>,
+    mut transport: ResMut<Transport>,
     mut scene_objects: ResMut<SceneObjects>,
+    mut scene_metadata: ResMut<SceneMetadata>,
+    query_all_objects: Query<Entity, Or<(With<Peg>, With<Ball>, With<BallSpawner>, With<Wall>)>>,
     mut spawn_event_writer: EventWriter<SpawnObject>,
+    mut history: ResMut<History>,
+    mut commands: Commands,
 ) {
-    for ev in ev_loaded.read() {
-        *scene_objects = rmp_serde::from_slice(&ev.contents).unwrap();
+    for ReplaceScene(file) in replace_scene_events.read() {
+        for e in query_all_objects.iter() {
+            commands.entity(e).despawn();
+        }
+        performance.started = false;
+        transport.start_queued = false;
+        history.clear();
+        *scene_metadata = file.metadata.clone();
+        *scene_objects = file.scene.clone();
         for (id, object) in scene_objects.objects.iter() {
             spawn_event_writer.send(SpawnObject(object.clone(), Some(*id)));
         }
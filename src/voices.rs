@@ -0,0 +1,154 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::egui;
+use std::collections::{HashMap, VecDeque};
+use crate::simulation::Peg;
+
+/// Keeps the number of notes sounding at once under control, so a ball jittering on a peg can't
+/// pile up audio entities or clip the output.
+pub struct VoicePlugin;
+
+impl Plugin for VoicePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<VoiceSettings>()
+            .init_resource::<VoiceManager>()
+            .add_systems(Update, (limit_voices, forget_removed_pegs));
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct VoiceSettings {
+    /// The most notes that can sound at once. Past this, the oldest note is cut off.
+    pub max_voices: usize,
+    /// How soon a peg can play again after it was hit, in seconds.
+    pub cooldown: f32,
+    /// Turns the level down while many notes sound at once.
+    pub limiter: bool,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        VoiceSettings {
+            max_voices: 32,
+            cooldown: 0.05,
+            limiter: true,
+        }
+    }
+}
+
+/// When each peg last played, so hits that come too soon after the last one can be dropped.
+#[derive(Default)]
+pub struct Retrigger(HashMap<Entity, f32>);
+
+impl Retrigger {
+    /// Whether `peg` may play at `time` (in seconds). If so, that counts as its last hit.
+    pub fn allow(&mut self, peg: Entity, time: f32, cooldown: f32) -> bool {
+        if self.0.get(&peg).is_some_and(|&last| time - last < cooldown) {
+            return false;
+        }
+        self.0.insert(peg, time);
+        true
+    }
+
+    /// Drops a peg that no longer exists.
+    pub fn forget(&mut self, peg: Entity) {
+        self.0.remove(&peg);
+    }
+}
+
+/// The limiter keeps the estimated level of everything playing under this.
+const HEADROOM: f32 = 1.5;
+
+/// How quickly the limiter lets the level back up, per second.
+pub const RELEASE: f32 = 4.;
+
+#[derive(Resource)]
+pub struct VoiceManager {
+    /// Sounding notes, oldest first, with the volume each was started at.
+    voices: VecDeque<(Entity, f32)>,
+    pub retrigger: Retrigger,
+    /// The limiter's current gain.
+    gain: f32,
+}
+
+impl Default for VoiceManager {
+    fn default() -> Self {
+        VoiceManager { voices: VecDeque::new(), retrigger: Retrigger::default(), gain: 1. }
+    }
+}
+
+impl VoiceManager {
+    /// Cuts off the oldest notes until there is room for one more under `max_voices`.
+    pub fn make_room(&mut self, commands: &mut Commands, max_voices: usize) {
+        while self.voices.len() >= max_voices.max(1) {
+            let Some((oldest, _)) = self.voices.pop_front() else { break };
+            // Dropping the sink stops the sound.
+            commands.entity(oldest).despawn();
+        }
+    }
+
+    pub fn add(&mut self, entity: Entity, volume: f32) {
+        self.voices.push_back((entity, volume));
+    }
+
+    /// Forgets a note that finished on its own.
+    pub fn remove(&mut self, entity: Entity) {
+        self.voices.retain(|&(voice, _)| voice != entity);
+    }
+
+    /// The limiter's gain, which new notes should be started at.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+}
+
+/// Sets every sounding note's volume from the estimated level of all of them. Notes are assumed
+/// not to line up, so the level is their volumes summed as powers rather than amplitudes. The
+/// gain drops at once and recovers over a fraction of a second.
+fn limit_voices(
+    time: Res<Time<Real>>,
+    settings: Res<VoiceSettings>,
+    mut voice_manager: ResMut<VoiceManager>,
+    sinks: Query<&AudioSink>,
+    spatial_sinks: Query<&SpatialAudioSink>,
+) {
+    let level = voice_manager.voices.iter().map(|(_, volume)| volume * volume).sum::<f32>().sqrt();
+    let target = if settings.limiter { (HEADROOM / level).min(1.) } else { 1. };
+    let gain = voice_manager.gain;
+    let gain = if target < gain { target } else { (gain + RELEASE * time.delta_seconds()).min(target) };
+    if gain == voice_manager.gain {
+        return;
+    }
+    voice_manager.gain = gain;
+    for &(entity, volume) in &voice_manager.voices {
+        if let Ok(sink) = sinks.get(entity) {
+            sink.set_volume(volume * gain);
+        } else if let Ok(sink) = spatial_sinks.get(entity) {
+            sink.set_volume(volume * gain);
+        }
+    }
+}
+
+/// Pegs are despawned when they are deleted and when a scene is loaded or cleared, so this keeps
+/// `Retrigger` from growing for as long as the editor is open.
+fn forget_removed_pegs(mut removed_pegs: RemovedComponents<Peg>, mut voice_manager: ResMut<VoiceManager>) {
+    for peg in removed_pegs.read() {
+        voice_manager.retrigger.forget(peg);
+    }
+}
+
+/// The side panel's voice settings.
+#[derive(SystemParam)]
+pub struct VoicesUi<'w> {
+    settings: ResMut<'w, VoiceSettings>,
+    voice_manager: Res<'w, VoiceManager>,
+}
+
+impl VoicesUi<'_> {
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.settings.max_voices, 1..=128).text("Polyphony"));
+        ui.add(egui::Slider::new(&mut self.settings.cooldown, 0.0..=0.5).text("Retrigger cooldown").suffix(" s"));
+        ui.checkbox(&mut self.settings.limiter, "Limiter");
+        ui.label(format!("{} sounding, gain {:.2}", self.voice_manager.voices.len(), self.voice_manager.gain));
+    }
+}
@@ -0,0 +1,156 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui, EguiContexts};
+use bevy_rapier2d::prelude::*;
+use crate::camera::MainCamera;
+use crate::pegs::{drag_peg, SelectedObject};
+use crate::simulation::{Object, SceneObjects, SpawnObject, PEG_RESTITUTION};
+
+/// Drawing and editing for walls: silent line segments and polylines that balls bounce off, for
+/// ramps, funnels and bumpers.
+pub struct WallPlugin;
+
+impl Plugin for WallPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<WallDraft>()
+            .add_systems(FixedUpdate, draw_wall.after(drag_peg))
+            .add_systems(Update, show_walls);
+    }
+}
+
+/// Marks the entities of walls.
+#[derive(Component)]
+pub struct Wall;
+
+/// How thick walls are, in pixels. Walls are rounded capsules rather than bare lines so fast
+/// balls can't slip between two physics steps as easily.
+const THICKNESS: f32 = 6.;
+
+/// Corners closer together than this, in pixels, are merged.
+const MIN_SEGMENT: f32 = 10.;
+
+/// How close the cursor has to be to a wall, in pixels, to pick it up.
+pub const GRAB_DISTANCE: f32 = 10.;
+
+/// The bounce of new walls, the same as a peg's. Wall bounce is edited per wall, unlike a peg's.
+pub const DEFAULT_RESTITUTION: f32 = PEG_RESTITUTION;
+
+/// The friction of new walls. 1 grips the ball as much as a peg does.
+pub const DEFAULT_FRICTION: f32 = 1.;
+
+/// A wall's collider and bounciness. `points` are relative to the entity, which sits at the first
+/// point.
+pub fn wall_body(points: &[[f32; 2]], restitution: f32, friction: f32) -> impl Bundle {
+    let origin = Vec2::from(points[0]);
+    let segments = points
+        .windows(2)
+        .map(|pair| (Vec2::ZERO, 0., Collider::capsule(Vec2::from(pair[0]) - origin, Vec2::from(pair[1]) - origin, THICKNESS / 2.)))
+        .collect();
+    (
+        RigidBody::Fixed,
+        Collider::compound(segments),
+        // Balls have no restitution of their own, so this is the wall's alone, as with pegs.
+        Restitution {
+            coefficient: restitution,
+            combine_rule: CoefficientCombineRule::Max,
+        },
+        // Balls and pegs both have the default friction of 0.5, which pegs average to 0.5;
+        // multiplying it by the ball's keeps 1 the same as a peg and lets 0 be frictionless.
+        Friction {
+            coefficient: friction,
+            combine_rule: CoefficientCombineRule::Multiply,
+        },
+    )
+}
+
+/// The distance from `position` to the nearest point of the polyline through `points`.
+pub fn distance(points: &[[f32; 2]], position: Vec2) -> f32 {
+    points
+        .windows(2)
+        .map(|pair| {
+            let (start, end) = (Vec2::from(pair[0]), Vec2::from(pair[1]));
+            let along = ((position - start).dot(end - start) / (end - start).length_squared()).clamp(0., 1.);
+            position.distance(start + along * (end - start))
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// The corners of the wall being drawn, with the last one following the cursor. Empty when no
+/// wall is being drawn.
+#[derive(Resource, Default)]
+pub struct WallDraft(Vec<Vec2>);
+
+/// Dragging from empty space draws a wall. Space pins a corner mid-drag, so one drag can draw a
+/// ramp or funnel.
+fn draw_wall(
+    input: Res<ButtonInput<MouseButton>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    primary_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    selected_object: Res<SelectedObject>,
+    mut draft: ResMut<WallDraft>,
+    mut spawn_event_writer: EventWriter<SpawnObject>,
+) {
+    let (camera, camera_transform) = primary_camera.single();
+    let Some(position) = primary_window
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+    else {
+        return;
+    };
+    if input.just_pressed(MouseButton::Left) {
+        // `drag_peg` has already run, and selects whatever was under the cursor. Shift-dragging
+        // draws a bar instead.
+        let shift = keyboard_input.pressed(KeyCode::ShiftLeft) || keyboard_input.pressed(KeyCode::ShiftRight);
+        if !shift && selected_object.0.is_none() && !contexts.ctx_mut().wants_pointer_input() {
+            draft.0 = vec![position, position];
+        }
+    } else if input.pressed(MouseButton::Left) {
+        let Some(last) = draft.0.last_mut() else { return };
+        *last = position;
+        if keyboard_input.just_pressed(KeyCode::Space) {
+            draft.0.push(position);
+        }
+    } else if !draft.0.is_empty() {
+        let mut points: Vec<[f32; 2]> = Vec::new();
+        for corner in std::mem::take(&mut draft.0) {
+            if !points.last().is_some_and(|&last| Vec2::from(last).distance(corner) < MIN_SEGMENT) {
+                points.push(corner.into());
+            }
+        }
+        // A click without a drag only clears the selection.
+        if points.len() >= 2 {
+            let wall = Object::Wall { points, restitution: DEFAULT_RESTITUTION, friction: DEFAULT_FRICTION };
+            spawn_event_writer.send(SpawnObject(wall, None));
+        }
+    }
+}
+
+fn show_walls(
+    mut gizmos: Gizmos,
+    scene_objects: Res<SceneObjects>,
+    selected_object: Res<SelectedObject>,
+    draft: Res<WallDraft>,
+) {
+    for (&id, object) in scene_objects.objects.iter() {
+        let Object::Wall { points, restitution, .. } = object else { continue };
+        // Bumpers, which give balls back more than they hit with, glow orange.
+        let mut color = if *restitution > 1. { Color::rgb(1.5, 0.6, 0.1) } else { Color::rgb(0.6, 0.6, 0.9) };
+        if selected_object.0 == Some(id) {
+            color = Color::rgb_from_array(color.rgb_to_vec3() * 3.);
+        }
+        gizmos.linestrip_2d(points.iter().map(|&point| Vec2::from(point)), color);
+    }
+    gizmos.linestrip_2d(draft.0.iter().copied(), Color::GRAY);
+}
+
+/// Edits how a wall bounces balls.
+pub fn wall_editor(ui: &mut egui::Ui, restitution: &mut f32, friction: &mut f32) {
+    ui.add(egui::Slider::new(restitution, 0.0..=1.5).text("Bounce"))
+        .on_hover_text("Above 1, the wall is a bumper that throws balls back faster than they came");
+    ui.add(egui::Slider::new(friction, 0.0..=2.).text("Friction"))
+        .on_hover_text("1 grips balls as much as a peg; 0 lets them slide freely");
+}